@@ -0,0 +1,876 @@
+//! Segmented (multi-connection) downloader
+//! Author: Ali Asadi  | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! Behavior:
+//! - Splits a range-capable file into `parts` RangeReq's (inclusive ends)
+//! - Each range is fetched on its own connection and written at its offset
+//! - A connection that finishes early steals half of the largest remaining
+//!   segment (never below MIN_SPLIT_SIZE), so slow parts don't drag the tail
+//! - Falls back to `download_single` when ranges/size are unknown or parts ≤ 1
+//! - Per-segment progress goes to .state (up front, every second, and on
+//!   Ctrl+C/error), so a resume picks up every segment where it stopped
+//! - Checks free space first; preallocates real blocks when enabled
+//! - Starting over old data (--no-resume, overwrite): nothing is truncated or
+//!   written until segment 0's first chunk has passed the page check (Gate)
+//! - Writes into `<name>.tondar.part`; renamed to `<name>` only when complete
+//!   (and, with `--checksum`, only when the digest matches; until then the
+//!   .state stays, so a bad file still shows up in `list` and can be resumed)
+//! - A guessed extension (MetaInfo.ext_guessed) is checked against the magic
+//!   bytes of the first chunk; a mismatch commits under the right one
+//! - Metalink piece hashes: failing pieces are fetched again before the rename
+//! - Multi-source: with `opts.sources`, each segment goes to the live source with
+//!   the fewest connections; a source that keeps failing or falls far behind the
+//!   best one is dropped and its segments are picked up by the others
+
+use crate::engine::prelude::*;
+use crate::engine::consts::{MAX_SOURCE_ERRORS, MIN_SPLIT_SIZE, SLOW_SOURCE_FACTOR, SLOW_SOURCE_SECS};
+use crate::engine::types::{DlOpts, PathPlan, RangeReq};
+use crate::iox::{file as iox, state as dlstate};
+use crate::net::inspect::MetaInfo;
+use crate::net::page;
+use crate::net::retry::{self, Backoff, Verdict};
+use crate::util::checksum::{Pieces, PrefixHasher};
+use crate::util::mime;
+use super::single::{self, make_request, progress_bar, stream_error};
+
+use futures_util::StreamExt;
+use indicatif::ProgressBar;
+use reqwest::{Client, StatusCode};
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Duration;
+
+/// One segment of the output file; `pos` is the next byte to write.
+#[derive(Debug, Clone, Copy)]
+struct Seg {
+    range: RangeReq,
+    pos: u64,
+    /// a worker is currently responsible for this segment
+    owned: bool,
+}
+
+type Segs = Arc<Mutex<Vec<Seg>>>;
+
+/// One server of the file (the probed URL first, then `opts.sources`).
+#[derive(Debug)]
+struct Source {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    alive: bool,
+    /// errors since it last sent data
+    errors: u32,
+    /// connections currently streaming from it
+    conns: usize,
+    bytes: u64,
+    /// `bytes` at the last tick, and for how many ticks in a row it was slow
+    last_bytes: u64,
+    slow_secs: u32,
+}
+
+type Sources = Arc<Mutex<Vec<Source>>>;
+
+/// download_multi: fetch `meta.final_url` into the plan's .part file over
+/// `opts.parts` connections, then rename it to the final name.
+/// Returns the plan it was committed under (another extension, if the content said so).
+pub async fn download_multi(
+    client: &Client,
+    meta: &MetaInfo,
+    plan: &PathPlan,
+    opts: &DlOpts,
+) -> Result<PathPlan> {
+    let part = plan.temp_path();
+    // an explicit --checksum wins over one the probe found
+    let opts = &DlOpts {
+        checksum: opts.checksum.clone().or_else(|| meta.checksum.clone()),
+        sniffed: Default::default(),
+        ..opts.clone()
+    };
+
+    // Disk-space preflight: fail now rather than with ENOSPC halfway through.
+    if let Some(total) = meta.size {
+        let have = match tokio::fs::metadata(&part).await {
+            Ok(m) if opts.resume => m.len(),
+            _ => 0,
+        };
+        iox::ensure_free_space(&plan.dir, total.saturating_sub(have))?;
+    }
+
+    let mut hasher = opts.checksum.as_ref().map(|c| PrefixHasher::new(c.algo));
+    download_segmented(client, meta, &part, opts, hasher.as_mut()).await?;
+
+    if let Some(pieces) = &opts.pieces {
+        if repair_pieces(client, meta, &part, pieces, opts).await? {
+            // bytes changed under the running digest: hash the file again
+            if let Some(h) = hasher.as_mut() {
+                h.catch_up(&part, 0).await?;
+            }
+        }
+    }
+
+    // Verify before the rename: a bad file stays as .part for inspection.
+    if let (Some(mut h), Some(expected)) = (hasher, &opts.checksum) {
+        let len = tokio::fs::metadata(&part).await.map_err(DmError::Io)?.len();
+        h.catch_up(&part, len).await?;
+        h.verify(expected)?;
+    }
+    // only now is the .part known good; a mismatch above keeps its .state for list/resume
+    let _ = dlstate::remove_state(&part).await;
+    if !meta.ext_guessed {
+        iox::commit_part(plan).await?;
+        return Ok(plan.clone());
+    }
+    commit_retyped(plan, opts).await
+}
+
+/// commit_retyped: commit a file whose extension we guessed; if its first bytes
+/// say otherwise it gets the right one (" (n)" when that name is taken)
+async fn commit_retyped(plan: &PathPlan, opts: &DlOpts) -> Result<PathPlan> {
+    let sniffed = match opts.sniffed.get() {
+        Some(found) => Some(*found),
+        // resumed: the first bytes came in an earlier run
+        None => {
+            use tokio::io::AsyncReadExt;
+            let mut head = Vec::new();
+            let file = tokio::fs::File::open(plan.temp_path()).await.map_err(DmError::Io)?;
+            file.take(512).read_to_end(&mut head).await.map_err(DmError::Io)?;
+            mime::sniff(&head)
+        }
+    };
+    let name = &plan.final_name;
+    let current = mime::extension(name);
+    let fits = |exts: &[&str]| current.is_some_and(|e| exts.contains(&e.to_ascii_lowercase().as_str()));
+    let Some((kind, exts)) = sniffed.filter(|(_, exts)| !fits(exts)) else {
+        iox::commit_part(plan).await?;
+        return Ok(plan.clone());
+    };
+
+    let stem = match current {
+        Some(e) => &name[..name.len() - e.len() - 1],
+        None => name.as_str(),
+    };
+    let target = PathPlan::new(&plan.dir, &format!("{stem}.{}", exts[0]));
+    let mut n = 0;
+    loop {
+        let candidate = if n == 0 { target.clone() } else { target.numbered(n) };
+        match iox::commit_part_as(plan, &candidate).await {
+            Ok(()) => {
+                println!("{name}: content is {kind}; saved as {}", candidate.final_name);
+                return Ok(candidate);
+            }
+            Err(DmError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn download_segmented(
+    client: &Client,
+    meta: &MetaInfo,
+    filename: &str,
+    opts: &DlOpts,
+    mut hasher: Option<&mut PrefixHasher>,
+) -> Result<()> {
+    let total = match meta.size {
+        Some(n) if meta.accept_ranges && opts.parts > 1 && n > 0 => n,
+        _ => return single::download_single(client, meta, filename, opts, hasher).await,
+    };
+
+    // Partial data from an earlier run: continue from its saved segments;
+    // without a usable .state only the single path knows how to resume.
+    let (file, existing) = iox::open_for_resume(filename).await?;
+    let mut gated = false;
+    let resumed = if existing > 0 && opts.resume {
+        match dlstate::load_state_for(filename).await? {
+            Some(st) if st.total == Some(total) && !st.segments.is_empty() => Some(st.segments),
+            _ => {
+                drop(file);
+                return single::download_single(client, meta, filename, opts, hasher).await;
+            }
+        }
+    } else {
+        None
+    };
+
+    let segs: Vec<Seg> = match resumed {
+        Some(saved) => saved
+            .into_iter()
+            .map(|s| Seg { range: RangeReq { start: s.start, end: s.end }, pos: s.pos, owned: false })
+            .collect(),
+        None => {
+            if existing == 0 {
+                iox::preallocate_if_needed(&file, Some(total), 0, opts.preallocate).await?;
+            } else {
+                gated = true;
+            }
+            split_ranges(total, opts.parts)
+                .into_iter()
+                .map(|r| Seg { range: r, pos: r.start, owned: false })
+                .collect()
+        }
+    };
+    drop(file);
+    let done: u64 = segs.iter().map(|s| s.pos - s.range.start).sum();
+    let segs: Segs = Arc::new(Mutex::new(segs));
+
+    // The .state is written before any data so a crash never leaves a
+    // preallocated file without a record of what is actually on disk.
+    // Behind a gate the old .state stays until the old data goes.
+    let mut state = dlstate::DlState::new(
+        &meta.final_url,
+        filename,
+        Some(total),
+        meta.etag.clone(),
+        meta.last_modified.clone(),
+    );
+    state.checksum = opts.checksum.as_ref().map(|c| c.to_string());
+    state.headers = opts.headers.clone();
+    let gate = match gated {
+        true => {
+            snapshot_segments(&mut state, &segs);
+            Some(Arc::new(Gate::new(state.clone(), opts.preallocate)))
+        }
+        false => {
+            save_segments(&mut state, &segs).await?;
+            None
+        }
+    };
+    let opened = |gate: &Option<Arc<Gate>>| gate.as_ref().is_none_or(|g| g.is_open());
+
+    let pb = progress_bar(opts, total, done);
+    let sources = make_sources(meta, opts);
+    let mut set = spawn_workers(client, meta, &sources, filename, &segs, &pb, opts, gate.clone());
+
+    // Every second: persist progress and let the digest follow the contiguous
+    // prefix, so only the tail is left to hash once the workers are done.
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    tick.tick().await;
+    let mut failure: Option<DmError> = None;
+    loop {
+        tokio::select! {
+            joined = set.join_next() => {
+                let Some(joined) = joined else { break };
+                let res = joined.unwrap_or_else(|e| Err(DmError::Other(format!("worker panicked: {e}"))));
+                if let Err(e) = res {
+                    if failure.is_none() {
+                        set.abort_all();
+                        failure = Some(e);
+                    }
+                }
+            }
+            _ = tick.tick() => {
+                drop_slow_sources(&sources);
+                if opened(&gate) {
+                    let _ = save_segments(&mut state, &segs).await;
+                }
+                if let (Some(h), None) = (hasher.as_deref_mut(), &failure) {
+                    if let Err(e) = h.catch_up(filename, state.written).await {
+                        set.abort_all();
+                        failure = Some(e);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut file = OpenOptions::new().write(true).open(filename).await.map_err(DmError::Io)?;
+    iox::finalize_sync(&mut file).await?;
+
+    let Some(err) = failure else {
+        if let Some(h) = hasher {
+            h.catch_up(filename, total).await?;
+        }
+        pb.finish_with_message("Done");
+        // the caller drops the .state once the digest has matched
+        let _ = save_segments(&mut state, &segs).await;
+        return Ok(());
+    };
+
+    // Data is synced; record every segment's progress for the next resume.
+    if opened(&gate) {
+        let _ = save_segments(&mut state, &segs).await;
+    }
+
+    if matches!(&err, DmError::Other(msg) if msg == "cancelled") {
+        pb.abandon_with_message("Paused");
+        eprintln!("Interrupted by user (Ctrl+C). State saved.");
+    } else {
+        pb.abandon_with_message("Failed");
+    }
+    Err(err)
+}
+
+/// spawn_workers: `opts.parts` connections sharing the segment and source tables
+#[allow(clippy::too_many_arguments)]
+fn spawn_workers(
+    client: &Client,
+    meta: &MetaInfo,
+    sources: &Sources,
+    filename: &str,
+    segs: &Segs,
+    pb: &ProgressBar,
+    opts: &DlOpts,
+    gate: Option<Arc<Gate>>,
+) -> JoinSet<Result<()>> {
+    let mut set = JoinSet::new();
+    for _ in 0..opts.parts.max(1) {
+        let w = Worker {
+            client: client.clone(),
+            meta: meta.clone(),
+            sources: sources.clone(),
+            filename: filename.to_string(),
+            segs: segs.clone(),
+            pb: pb.clone(),
+            opts: opts.clone(),
+            gate: gate.clone(),
+        };
+        set.spawn(async move { w.run().await });
+    }
+    set
+}
+
+/// repair_pieces: check every piece hash and fetch the failing pieces again, once.
+/// `Ok(true)` when something was re-downloaded; a piece that is still bad is an error.
+async fn repair_pieces(
+    client: &Client,
+    meta: &MetaInfo,
+    filename: &str,
+    pieces: &Pieces,
+    opts: &DlOpts,
+) -> Result<bool> {
+    let bad = pieces.bad_pieces(filename).await?;
+    let Some((idx, actual)) = bad.first().cloned() else {
+        return Ok(false);
+    };
+    let total = match meta.size {
+        Some(n) if meta.accept_ranges => n,
+        _ => return Err(piece_mismatch(pieces, idx, actual)),
+    };
+
+    eprintln!("{} piece(s) failed verification; fetching them again…", bad.len());
+    let segs: Vec<Seg> = bad
+        .iter()
+        .map(|&(i, _)| pieces.range(i, total))
+        .map(|r| Seg { range: r, pos: r.start, owned: false })
+        .collect();
+    let segs: Segs = Arc::new(Mutex::new(segs));
+    let sources = make_sources(meta, opts);
+    let mut set = spawn_workers(client, meta, &sources, filename, &segs, &ProgressBar::hidden(), opts, None);
+    while let Some(joined) = set.join_next().await {
+        joined.unwrap_or_else(|e| Err(DmError::Other(format!("worker panicked: {e}"))))?;
+    }
+
+    match pieces.bad_pieces(filename).await?.into_iter().next() {
+        Some((idx, actual)) => Err(piece_mismatch(pieces, idx, actual)),
+        None => Ok(true),
+    }
+}
+
+/// make_sources: the probed URL plus every extra source, all live
+fn make_sources(meta: &MetaInfo, opts: &DlOpts) -> Sources {
+    let sources = std::iter::once(meta)
+        .chain(&opts.sources)
+        .map(|m| Source {
+            url: m.final_url.clone(),
+            // validators are sent only if --if-range was used
+            etag: if opts.if_range { m.etag.clone() } else { None },
+            last_modified: if opts.if_range { m.last_modified.clone() } else { None },
+            alive: true,
+            errors: 0,
+            conns: 0,
+            bytes: 0,
+            last_bytes: 0,
+            slow_secs: 0,
+        })
+        .collect();
+    Arc::new(Mutex::new(sources))
+}
+
+/// pick_source: the live source with the fewest connections
+fn pick_source(sources: &Sources) -> usize {
+    let sources = sources.lock().unwrap();
+    sources
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.alive)
+        .min_by_key(|(_, s)| s.conns)
+        .map_or(0, |(i, _)| i)
+}
+
+/// source_failed: count an error against source `src` and drop it once it has
+/// failed MAX_SOURCE_ERRORS times without sending data in between (an error hours
+/// after the last one is a new stall), or at once when retrying it is pointless
+/// (404, 401…); the last live source is never dropped.
+/// `true` when it was dropped (the caller should switch, not back off).
+fn source_failed(sources: &Sources, src: usize, err: &DmError) -> bool {
+    let mut sources = sources.lock().unwrap();
+    let live = sources.iter().filter(|s| s.alive).count();
+    let s = &mut sources[src];
+    s.errors += 1;
+    if !s.alive {
+        return true;
+    }
+    if (s.errors >= MAX_SOURCE_ERRORS || retry::classify(err) != Verdict::Retry) && live > 1 {
+        s.alive = false;
+        eprintln!("Dropping mirror {}: {err}", s.url);
+        return true;
+    }
+    false
+}
+
+/// source_progress: `n` bytes arrived from source `src`; its error count starts over
+fn source_progress(sources: &Sources, src: usize, n: u64) {
+    let s = &mut sources.lock().unwrap()[src];
+    s.bytes += n;
+    s.errors = 0;
+}
+
+/// drop_slow_sources: once a second, compare per-connection throughput; a source
+/// slower than best / SLOW_SOURCE_FACTOR for SLOW_SOURCE_SECS ticks in a row is dropped
+fn drop_slow_sources(sources: &Sources) {
+    let mut sources = sources.lock().unwrap();
+    let rates: Vec<Option<u64>> = sources
+        .iter_mut()
+        .map(|s| {
+            let delta = s.bytes - s.last_bytes;
+            s.last_bytes = s.bytes;
+            (s.alive && s.conns > 0).then(|| delta / s.conns as u64)
+        })
+        .collect();
+    let Some(best) = rates.iter().flatten().max().copied() else { return };
+    for (s, rate) in sources.iter_mut().zip(&rates) {
+        match rate {
+            Some(r) if r * SLOW_SOURCE_FACTOR < best => s.slow_secs += 1,
+            _ => s.slow_secs = 0,
+        }
+    }
+    for i in 0..sources.len() {
+        let live = sources.iter().filter(|s| s.alive).count();
+        let s = &mut sources[i];
+        if s.alive && s.slow_secs >= SLOW_SOURCE_SECS && live > 1 {
+            s.alive = false;
+            eprintln!("Dropping slow mirror {}", s.url);
+        }
+    }
+}
+
+fn piece_mismatch(pieces: &Pieces, idx: usize, actual: String) -> DmError {
+    DmError::PieceMismatch {
+        piece: idx,
+        algo: pieces.algo.name().to_string(),
+        expected: pieces.hashes[idx].clone(),
+        actual,
+    }
+}
+
+/// save_segments: snapshot segment progress into `state` and persist it
+async fn save_segments(state: &mut dlstate::DlState, segs: &Segs) -> Result<()> {
+    snapshot_segments(state, segs);
+    dlstate::save_state(state).await
+}
+
+fn snapshot_segments(state: &mut dlstate::DlState, segs: &Segs) {
+    let segs = segs.lock().unwrap();
+    state.written = contiguous_prefix(&segs);
+    state.segments = segs
+        .iter()
+        .map(|s| dlstate::SegmentState { start: s.range.start, end: s.range.end, pos: s.pos })
+        .collect();
+}
+
+struct Worker {
+    client: Client,
+    /// the probed file, for the page checks
+    meta: MetaInfo,
+    sources: Sources,
+    filename: String,
+    segs: Segs,
+    pb: ProgressBar,
+    opts: DlOpts,
+    gate: Option<Arc<Gate>>,
+}
+
+/// Gate: a fresh start over an old .part. Its bytes must survive a login page,
+/// so no worker writes until segment 0's first chunk is known to be the file;
+/// that worker then empties (and preallocates) the file and opens the gate.
+#[derive(Debug)]
+struct Gate {
+    open: watch::Sender<bool>,
+    /// the fresh .state (nothing done), saved as the old data goes
+    state: dlstate::DlState,
+    preallocate: bool,
+}
+
+impl Gate {
+    fn new(state: dlstate::DlState, preallocate: bool) -> Self {
+        Gate { open: watch::Sender::new(false), state, preallocate }
+    }
+
+    fn is_open(&self) -> bool {
+        *self.open.borrow()
+    }
+
+    /// release: the first chunk passed; drop the old data and let everyone write
+    async fn release(&self, file: &tokio::fs::File) -> Result<()> {
+        if self.is_open() {
+            return Ok(());
+        }
+        dlstate::save_state(&self.state).await?;
+        file.set_len(0).await.map_err(DmError::Io)?;
+        iox::preallocate_if_needed(file, self.state.total, 0, self.preallocate).await?;
+        self.open.send_replace(true);
+        Ok(())
+    }
+
+    /// wait: until segment 0 has released the gate (an abort ends it otherwise)
+    async fn wait(&self) {
+        let _ = self.open.subscribe().wait_for(|&open| open).await;
+    }
+}
+
+impl Worker {
+    /// run: claim unowned segments first, then keep stealing work until nothing is left to split
+    async fn run(&self) -> Result<()> {
+        while let Some(idx) = claim_or_steal(&self.segs, MIN_SPLIT_SIZE) {
+            self.run_segment(idx).await?;
+        }
+        Ok(())
+    }
+
+    /// run_segment: fetch segment `idx` to its end, retrying transient errors from `pos`;
+    /// a source that gets dropped is swapped for another live one
+    async fn run_segment(&self, idx: usize) -> Result<()> {
+        let mut backoff = Backoff::new(self.opts.retry);
+        let mut src = pick_source(&self.sources);
+        loop {
+            let before = self.segs.lock().unwrap()[idx].pos;
+            self.sources.lock().unwrap()[src].conns += 1;
+            let res = self.fetch(idx, src).await;
+            self.sources.lock().unwrap()[src].conns -= 1;
+            match res {
+                Ok(true) => return Ok(()),
+                // the source was dropped mid-stream: hand the rest back to the pool
+                Ok(false) => {
+                    self.segs.lock().unwrap()[idx].owned = false;
+                    return Ok(());
+                }
+                Err(e) if matches!(&e, DmError::Other(msg) if msg == "cancelled") => return Err(e),
+                Err(e) if source_failed(&self.sources, src, &e) => {
+                    src = pick_source(&self.sources);
+                    backoff.reset();
+                }
+                Err(e) => {
+                    if self.segs.lock().unwrap()[idx].pos > before {
+                        backoff.reset();
+                    }
+                    retry::wait(&mut backoff, e, &format!("Part {idx}"), &self.opts.cancel).await?;
+                }
+            }
+        }
+    }
+
+    /// fetch: stream segment `idx` from source `src`. `Ok(false)` when the source
+    /// was dropped before the segment was finished.
+    async fn fetch(&self, idx: usize, src: usize) -> Result<bool> {
+        let seg = self.segs.lock().unwrap()[idx];
+        if seg.pos > seg.range.end {
+            return Ok(true);
+        }
+
+        let (url, etag, last_modified) = {
+            let s = &self.sources.lock().unwrap()[src];
+            (s.url.clone(), s.etag.clone(), s.last_modified.clone())
+        };
+        let resp = make_request(
+            &self.client,
+            &self.opts.auth,
+            &url,
+            true,
+            seg.pos,
+            Some(seg.range.end),
+            etag.as_deref(),
+            last_modified.as_deref(),
+        )
+        .await?;
+        if !resp.status().is_success() {
+            return Err(retry::status_error(&url, resp.status(), resp.headers()));
+        }
+        // a 200 to a range: the server no longer honours ours (If-Range saw a change)
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(DmError::Stale(format!(
+                "{url}: expected 206 for bytes={}-{}, got {}",
+                seg.pos,
+                seg.range.end,
+                resp.status()
+            )));
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.filename)
+            .await
+            .map_err(DmError::Io)?;
+        file.seek(SeekFrom::Start(seg.pos)).await.map_err(DmError::Io)?;
+
+        let resp = page::check_response(&self.meta, &url, resp).await?;
+        let mut stream = resp.bytes_stream();
+        let mut pos = seg.pos;
+        // `end` may shrink while we stream if another connection steals our tail
+        let mut end = seg.range.end;
+        while pos <= end {
+            if self.opts.cancel.is_cancelled() {
+                iox::finalize_sync(&mut file).await?;
+                return Err(DmError::Other("cancelled".into()));
+            }
+            if !self.sources.lock().unwrap()[src].alive {
+                iox::finalize_sync(&mut file).await?;
+                return Ok(false);
+            }
+            // wake up every second even if the source stalls, to notice being dropped
+            let Ok(next) = tokio::time::timeout(Duration::from_secs(1), stream.next()).await else {
+                continue;
+            };
+            match next {
+                Some(Ok(chunk)) => {
+                    if pos == 0 {
+                        page::check_first_chunk(&self.meta, &url, &chunk)?;
+                        self.opts.note_head(&chunk);
+                        if let Some(gate) = &self.gate {
+                            gate.release(&file).await?;
+                        }
+                    } else if let Some(gate) = &self.gate {
+                        gate.wait().await;
+                    }
+                    self.opts.throttle(chunk.len() as u64).await;
+                    // never write past the segment end, even if the server sends more.
+                    // A steal racing this write only overlaps with identical bytes.
+                    end = self.segs.lock().unwrap()[idx].range.end;
+                    let room = (end + 1).saturating_sub(pos) as usize;
+                    let chunk = &chunk[..chunk.len().min(room)];
+                    file.write_all(chunk).await.map_err(DmError::Io)?;
+                    // `pos` must only cover bytes the OS has (state and digest read them back)
+                    file.flush().await.map_err(DmError::Io)?;
+                    pos += chunk.len() as u64;
+                    end = {
+                        let mut segs = self.segs.lock().unwrap();
+                        segs[idx].pos = pos;
+                        segs[idx].range.end
+                    };
+                    source_progress(&self.sources, src, chunk.len() as u64);
+                    self.pb.inc(chunk.len() as u64);
+                }
+                Some(Err(e)) => {
+                    iox::finalize_sync(&mut file).await?;
+                    return Err(stream_error(e, end + 1, pos));
+                }
+                None => break,
+            }
+        }
+        iox::finalize_sync(&mut file).await?;
+
+        // short read: the retry resumes this segment from `pos`
+        if pos <= end {
+            return Err(DmError::Truncated { expected: end + 1, got: pos });
+        }
+        Ok(true)
+    }
+}
+
+/// split_ranges: divide `total` bytes into at most `parts` inclusive ranges
+fn split_ranges(total: u64, parts: usize) -> Vec<RangeReq> {
+    let parts = (parts.max(1) as u64).min(total.max(1));
+    let chunk = total / parts;
+    (0..parts)
+        .map(|i| {
+            let start = i * chunk;
+            let end = if i == parts - 1 { total - 1 } else { start + chunk - 1 };
+            RangeReq { start, end }
+        })
+        .collect()
+}
+
+/// claim_or_steal: take an unfinished segment nobody is working on; failing that,
+/// split the unfinished segment with the most bytes left in half and take the
+/// upper half. `None` when every remainder is smaller than two `min_split` pieces.
+fn claim_or_steal(segs: &Segs, min_split: u64) -> Option<usize> {
+    let mut segs = segs.lock().unwrap();
+    if let Some(idx) = segs.iter().position(|s| !s.owned && s.pos <= s.range.end) {
+        segs[idx].owned = true;
+        return Some(idx);
+    }
+
+    let (victim, remaining) = segs
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (i, (s.range.end + 1).saturating_sub(s.pos)))
+        .max_by_key(|&(_, rem)| rem)?;
+    if remaining < min_split.max(1) * 2 {
+        return None;
+    }
+
+    let v = segs[victim];
+    let mid = v.pos + remaining / 2;
+    segs[victim].range.end = mid - 1;
+    segs.push(Seg { range: RangeReq { start: mid, end: v.range.end }, pos: mid, owned: true });
+    Some(segs.len() - 1)
+}
+
+/// contiguous_prefix: bytes from 0 that are known to be on disk
+fn contiguous_prefix(segs: &[Seg]) -> u64 {
+    let mut sorted: Vec<&Seg> = segs.iter().collect();
+    sorted.sort_by_key(|s| s.range.start);
+    let mut prefix = 0;
+    for s in sorted {
+        if s.range.start > prefix {
+            break;
+        }
+        prefix = prefix.max(s.pos);
+        if s.pos <= s.range.end {
+            break;
+        }
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(n: usize) -> Sources {
+        let s = (0..n)
+            .map(|i| Source {
+                url: format!("https://m{i}.example/f"),
+                etag: None,
+                last_modified: None,
+                alive: true,
+                errors: 0,
+                conns: 0,
+                bytes: 0,
+                last_bytes: 0,
+                slow_secs: 0,
+            })
+            .collect();
+        Arc::new(Mutex::new(s))
+    }
+
+    fn seg(start: u64, end: u64, pos: u64, owned: bool) -> Seg {
+        Seg { range: RangeReq { start, end }, pos, owned }
+    }
+
+    /// the ranges cover 0..total exactly once, in order
+    fn covers(ranges: &[RangeReq], total: u64) -> bool {
+        ranges.first().is_some_and(|r| r.start == 0)
+            && ranges.windows(2).all(|w| w[1].start == w[0].end + 1)
+            && ranges.last().is_some_and(|r| r.end == total - 1)
+    }
+
+    #[test]
+    fn split_uneven_sizes() {
+        let r = split_ranges(10, 3);
+        assert_eq!(r.iter().map(|r| (r.start, r.end)).collect::<Vec<_>>(), [(0, 2), (3, 5), (6, 9)]);
+        for (total, parts) in [(1_000_003, 4), (7, 7), (u32::MAX as u64 + 5, 16)] {
+            let r = split_ranges(total, parts);
+            assert_eq!(r.len(), parts);
+            assert!(covers(&r, total), "{total}/{parts}");
+        }
+    }
+
+    #[test]
+    fn split_small_files() {
+        // below MIN_SPLIT_SIZE the split still covers the file; never more parts than bytes
+        let small = MIN_SPLIT_SIZE / 2;
+        assert!(covers(&split_ranges(small, 4), small));
+        assert_eq!(split_ranges(3, 8).len(), 3);
+        assert!(covers(&split_ranges(3, 8), 3));
+        let one = split_ranges(1, 4);
+        assert_eq!(one.iter().map(|r| (r.start, r.end)).collect::<Vec<_>>(), [(0, 0)]);
+        assert_eq!(split_ranges(100, 0).len(), 1);
+    }
+
+    #[test]
+    fn claim_takes_unowned_work_first() {
+        let segs: Segs = Arc::new(Mutex::new(vec![seg(0, 99, 100, false), seg(100, 199, 120, false)]));
+        // the finished one is skipped, the half-done one is claimed as it is
+        assert_eq!(claim_or_steal(&segs, 1), Some(1));
+        assert!(segs.lock().unwrap()[1].owned);
+        assert_eq!(segs.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn steal_halves_the_largest_remainder() {
+        let segs: Segs = Arc::new(Mutex::new(vec![
+            seg(0, 999, 900, true),    // 100 left
+            seg(1000, 1999, 1200, true), // 800 left: the victim
+            seg(2000, 2999, 2600, true), // 400 left
+        ]));
+        assert_eq!(claim_or_steal(&segs, 100), Some(3));
+        let segs = segs.lock().unwrap();
+        assert_eq!((segs[1].range.start, segs[1].range.end, segs[1].pos), (1000, 1599, 1200));
+        assert_eq!((segs[3].range.start, segs[3].range.end, segs[3].pos, segs[3].owned), (1600, 1999, 1600, true));
+        assert_eq!(segs[0].range.end, 999);
+    }
+
+    #[test]
+    fn no_steal_below_two_min_pieces() {
+        let segs: Segs = Arc::new(Mutex::new(vec![seg(0, 999, 801, true)]));
+        assert_eq!(claim_or_steal(&segs, 100), None);
+        assert_eq!(claim_or_steal(&segs, 99), Some(1));
+        let segs = segs.lock().unwrap();
+        assert_eq!((segs[0].range.end, segs[1].range.start), (899, 900));
+    }
+
+    #[test]
+    fn writer_already_past_the_stolen_end() {
+        // seg 0's tail was stolen at 80, but its worker had already written up to 90:
+        // the overlap holds identical bytes, so 0..90 is on disk
+        let mut segs = vec![seg(0, 79, 90, true), seg(80, 99, 80, true)];
+        assert_eq!(contiguous_prefix(&segs), 90);
+        // nobody claims or splits the overshot segment again
+        let shared: Segs = Arc::new(Mutex::new(segs.clone()));
+        shared.lock().unwrap()[0].owned = false;
+        assert_eq!(claim_or_steal(&shared, 1), Some(2));
+        assert_eq!(shared.lock().unwrap()[1].range.end, 89);
+        // the thief reaches the end too
+        segs[1].pos = 100;
+        assert_eq!(contiguous_prefix(&segs), 100);
+    }
+
+    #[test]
+    fn prefix_for_resume() {
+        assert_eq!(contiguous_prefix(&[]), 0);
+        // segment 1 finished before segment 0: only 0's progress counts
+        let segs = [seg(0, 99, 40, true), seg(100, 199, 200, true)];
+        assert_eq!(contiguous_prefix(&segs), 40);
+        // order in the vector does not matter (stolen halves are pushed at the end)
+        let segs = [seg(150, 199, 160, true), seg(0, 99, 100, true), seg(100, 149, 150, true)];
+        assert_eq!(contiguous_prefix(&segs), 160);
+        let segs = [seg(0, 99, 0, false), seg(100, 199, 200, true)];
+        assert_eq!(contiguous_prefix(&segs), 0);
+    }
+
+    #[test]
+    fn mirror_errors_start_over_after_data() {
+        let src = sources(2);
+        let err = DmError::Network("connection reset".into());
+        for _ in 0..5 {
+            assert!(!source_failed(&src, 1, &err));
+            source_progress(&src, 1, 4096);
+        }
+        assert!(src.lock().unwrap()[1].alive);
+        // errors in a row without data in between still drop it
+        for _ in 1..MAX_SOURCE_ERRORS {
+            assert!(!source_failed(&src, 1, &err));
+        }
+        assert!(source_failed(&src, 1, &err));
+        assert!(!src.lock().unwrap()[1].alive);
+        // the last live source is kept
+        for _ in 0..MAX_SOURCE_ERRORS {
+            assert!(!source_failed(&src, 0, &err));
+        }
+        assert!(src.lock().unwrap()[0].alive);
+    }
+}
@@ -1,38 +1,71 @@
 //! Single-part downloader with robust resume, Ctrl+C, and light retry
 //! Author: Ali Asadi  | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
 //! Behavior:
 //! - Uses final CDN URL + (optional) If-Range
 //! - Resumes from local file length, seeks to exact offset
 //! - Handles 416 by retrying from (offset-1) and discarding the first byte
 //! - Graceful Ctrl+C: flush+sync, save .state, exit cleanly
 //! - Saves .state every ~1MiB to aid debugging/crash-resume
-//! - Light retry (3 attempts) on transient network errors
-
-//! Single-part downloader with robust resume, Ctrl+C, and light retry
-//! Author: Ali Asadi  | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//! - Retries go through net::retry (status classes, Retry-After, jittered backoff);
+//!   403/404… come back as DmError::Stale so the caller can re-probe the link
+//! - Optional digest computed while streaming (resumed prefix is re-hashed first)
+//! - A stream that ends before MetaInfo.size / Content-Length is a short read:
+//!   it is retried as a resume and surfaces as DmError::Truncated if it persists
+//! - An HTML page instead of the file (net::page) stops the job before any
+//!   existing data is truncated or overwritten
+//! - The .state outlives a finished transfer: download_multi drops it once the
+//!   digest (if any) has matched
 
 use crate::engine::prelude::*;
+use crate::engine::types::DlOpts;
+use crate::http::auth::Auth;
 use crate::iox::{file as iox, state as dlstate};
 use crate::iox::file::finalize_sync;
+use crate::net::inspect::MetaInfo;
+use crate::net::page;
+use crate::net::retry::{self, Backoff};
+use crate::util::checksum::PrefixHasher;
 
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::{Client, Response, StatusCode};
 use tokio::fs::File;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
-use tokio::time::{sleep, Duration};
 use std::io::SeekFrom;
 
 pub async fn download_single(
     client: &Client,
-    url: &str,                     // final CDN URL
+    meta: &MetaInfo,               // probed meta (final CDN URL, size, validators)
     filename: &str,                // output file name
-    size: Option<u64>,             // total size if known
-    ranges_supported: bool,
-    etag: Option<&str>,            // pass Some(..) only if --if-range was used
-    last_modified: Option<&str>,   // pass Some(..) only if --if-range was used
+    opts: &DlOpts,
+    mut hasher: Option<&mut PrefixHasher>, // digest of [0, written), kept while streaming
 ) -> Result<()> {
-    let (mut file, existing) = iox::open_for_resume(filename).await?;
+    let url = meta.final_url.as_str();
+    let size = meta.size;
+    let ranges_supported = meta.accept_ranges;
+    // validators are sent only if --if-range was used
+    let etag = if opts.if_range { meta.etag.as_deref() } else { None };
+    let last_modified = if opts.if_range { meta.last_modified.as_deref() } else { None };
+
+    let (mut file, mut existing) = iox::open_for_resume(filename).await?;
+
+    // Resume disabled → start from zero; the old bytes are dropped only once the
+    // first chunk has passed the page check (`fresh` in the stream loop)
+    if !opts.resume && existing > 0 {
+        existing = 0;
+    }
+
+    // A .state knows better than the file length (preallocated or segmented
+    // files can be full-size with holes): only its contiguous prefix is trusted.
+    if existing > 0 {
+        if let Some(st) = dlstate::load_state_for(filename).await? {
+            if st.written < existing {
+                file.set_len(st.written).await.map_err(DmError::Io)?;
+                existing = st.written;
+            }
+        }
+    }
 
     // If local file is larger than total (from a previous bug), shrink it.
     let mut start_offset = match size {
@@ -43,9 +76,9 @@ pub async fn download_single(
         _ => existing,
     };
 
-    // If server doesn't support ranges but we have partial data → restart from zero.
+    // If server doesn't support ranges but we have partial data → restart from zero
+    // (the old bytes are only dropped once the new first chunk has passed the page check)
     if !ranges_supported && start_offset > 0 {
-        file.set_len(0).await.map_err(DmError::Io)?;
         start_offset = 0;
     }
 
@@ -53,42 +86,57 @@ pub async fn download_single(
     if let Some(total) = size {
         if start_offset >= total {
             eprintln!("Already complete (local {} bytes, total {}). Skipping.", start_offset, total);
-            let _ = dlstate::remove_state(filename).await;
             return Ok(());
         }
     }
 
-    // Ctrl+C handler: one-shot signal channel
-    let (cancel_tx, mut cancel_rx) = tokio::sync::oneshot::channel::<()>();
-    tokio::spawn(async move {
-        let _ = tokio::signal::ctrl_c().await;
-        let _ = cancel_tx.send(());
-    });
+    let mut state = dlstate::DlState::new(url, filename, size, meta.etag.clone(), meta.last_modified.clone());
+    state.written = start_offset;
+    state.checksum = opts.checksum.as_ref().map(|c| c.to_string());
+    state.headers = opts.headers.clone();
 
-    // Attempts with simple backoff
-    let mut attempt = 0usize;
+    // failed requests and broken streams go through the retry policy
+    let mut backoff = Backoff::new(opts.retry);
     let mut offset = start_offset;
 
     loop {
-        attempt += 1;
-
-        let mut resp = make_request(client, url, ranges_supported, offset, etag, last_modified).await?;
+        let mut resp = match make_request(client, &opts.auth, url, ranges_supported, offset, None, etag, last_modified).await {
+            Ok(r) => r,
+            Err(e) => {
+                retry::wait(&mut backoff, e, filename, &opts.cancel).await?;
+                continue;
+            }
+        };
 
         // Handle 416 Range Not Satisfiable
         if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
             if let Some(total) = size {
                 if offset >= total {
                     eprintln!("416 at offset {offset}; file seems complete. Skipping.");
-                    let _ = dlstate::remove_state(filename).await;
                     return Ok(());
                 }
             }
             // Try from offset-1, then seek to original offset before writing (drop first byte)
             let back = offset.saturating_sub(1);
             eprintln!("416 at {offset}; retrying from {back}…");
-            resp = make_request(client, url, ranges_supported, back, etag, last_modified).await?;
+            resp = match make_request(client, &opts.auth, url, ranges_supported, back, None, etag, last_modified).await {
+                Ok(r) => r,
+                Err(e) => {
+                    retry::wait(&mut backoff, e, filename, &opts.cancel).await?;
+                    continue;
+                }
+            };
         }
 
+        // 429/503 → wait and retry; 403/404… → let the caller re-probe; keep what is on disk
+        if !resp.status().is_success() {
+            let e = retry::status_error(url, resp.status(), resp.headers());
+            retry::wait(&mut backoff, e, filename, &opts.cancel).await?;
+            continue;
+        }
+        // a login/expired-link page instead of the file: stop before anything is truncated
+        let resp = page::check_response(meta, url, resp).await?;
+
         // Validate resume contract when resuming
         if offset > 0 && ranges_supported {
             use reqwest::header::CONTENT_RANGE;
@@ -112,7 +160,6 @@ pub async fn download_single(
 
             if !resume_ok {
                 eprintln!("Server did not clearly return resume; restarting from zero.");
-                file.set_len(0).await.map_err(DmError::Io)?;
                 offset = 0;
                 // Loop will retry from zero immediately.
                 continue;
@@ -121,13 +168,17 @@ pub async fn download_single(
 
         // Ensure we write at the exact offset
         file.seek(SeekFrom::Start(offset)).await.map_err(DmError::Io)?;
+        state.written = offset;
+        // re-hash whatever is already on disk before new bytes stream in
+        if let Some(h) = hasher.as_deref_mut() {
+            h.catch_up(filename, offset).await?;
+        }
 
         // Stream with progress, periodic state save, cancel, and error retry
-        match run_stream_to_file_with_state(
-            &mut file, resp, size, offset, url, filename, etag, last_modified, &mut cancel_rx
-        ).await {
+        match run_stream_to_file_with_state(&mut file, resp, meta, &mut state, opts, hasher.as_deref_mut()).await {
             Ok(_) => {
-                let _ = dlstate::remove_state(filename).await; // cleanup
+                // kept until the file is verified: a mismatch can still be listed and resumed
+                let _ = dlstate::save_state(&state).await;
                 return Ok(());
             }
             Err(e) => {
@@ -137,32 +188,39 @@ pub async fn download_single(
                     eprintln!("Interrupted by user (Ctrl+C). State saved.");
                     return Err(e);
                 }
-                // Transient network error? retry up to 3 times
-                if attempt < 3 {
-                    eprintln!("Network error: {}. Retrying (attempt {attempt}/3)…", e);
-                    sleep(Duration::from_secs(2_u64.pow(attempt as u32))).await;
-                    continue;
-                } else {
-                    return Err(e);
+                // bytes arrived before the break: this is a fresh stall, not the same one
+                if state.written > offset {
+                    backoff.reset();
                 }
+                retry::wait(&mut backoff, e, filename, &opts.cancel).await?;
+                // without ranges the server can only send it all again
+                offset = if ranges_supported { state.written } else { 0 };
             }
         }
     }
 }
 
-async fn make_request(
+/// make_request: GET from `start_offset` (to `end` inclusive, if given) with optional If-Range
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn make_request(
     client: &Client,
+    auth: &Auth,
     url: &str,
     ranges_supported: bool,
     start_offset: u64,
+    end: Option<u64>,
     etag: Option<&str>,
     last_modified: Option<&str>,
 ) -> Result<Response> {
     use reqwest::header::{IF_RANGE, RANGE};
     let mut req = client.get(url);
 
-    if ranges_supported && start_offset > 0 {
-        req = req.header(RANGE, format!("bytes={}-", start_offset));
+    if ranges_supported && (start_offset > 0 || end.is_some()) {
+        let range = match end {
+            Some(e) => format!("bytes={}-{}", start_offset, e),
+            None => format!("bytes={}-", start_offset),
+        };
+        req = req.header(RANGE, range);
         if let Some(tag) = etag {
             req = req.header(IF_RANGE, tag);
         } else if let Some(lm) = last_modified {
@@ -170,7 +228,7 @@ async fn make_request(
         }
     }
 
-    let resp = req.send().await.map_err(|e| DmError::Network(e.to_string()))?;
+    let resp = auth.send(req).await.map_err(retry::from_reqwest)?;
 
     // Debug once per request
     eprintln!(
@@ -183,21 +241,10 @@ async fn make_request(
     Ok(resp)
 }
 
-async fn run_stream_to_file_with_state(
-    file: &mut File,
-    resp: Response,
-    total_size: Option<u64>,
-    start_offset: u64,
-    url: &str,
-    filename: &str,
-    etag: Option<&str>,
-    last_modified: Option<&str>,
-    cancel_rx: &mut tokio::sync::oneshot::Receiver<()>,
-) -> Result<()> {
-    let expected = total_size.unwrap_or_else(|| start_offset + resp.content_length().unwrap_or(0));
-
-    let pb = ProgressBar::new(expected);
-    pb.set_position(start_offset);
+/// progress_bar: the shared bar style for single and multi downloads
+pub(crate) fn progress_bar(opts: &DlOpts, total: u64, position: u64) -> ProgressBar {
+    let pb = opts.progress.add(ProgressBar::new(total));
+    pb.set_position(position);
     pb.set_style(
         ProgressStyle::with_template(
             "{percent:>3}% {bar:40.cyan/blue} {bytes}/{total_bytes} ({bytes_per_sec}) elapsed {elapsed} ETA {eta}"
@@ -205,84 +252,112 @@ async fn run_stream_to_file_with_state(
         .unwrap()
         .progress_chars("##-"),
     );
+    pb
+}
 
+async fn run_stream_to_file_with_state(
+    file: &mut File,
+    resp: Response,
+    meta: &MetaInfo,
+    state: &mut dlstate::DlState,
+    opts: &DlOpts,
+    mut hasher: Option<&mut PrefixHasher>,
+) -> Result<()> {
+    let start_offset = state.written;
+    // what "complete" means: the probed size, else what this response promises
+    let promised = resp.content_length().map(|n| start_offset + n);
+    let expected = state.total.or(promised).unwrap_or(start_offset);
+
+    let pb = progress_bar(opts, expected, start_offset);
+
+    let url = resp.url().to_string();
     let mut stream = resp.bytes_stream();
-    let mut written = start_offset;
-    let mut last_state_dump = written;
+    let mut last_state_dump = state.written;
+    // from zero: whatever an earlier attempt left goes once the first chunk is known good
+    let mut fresh = start_offset == 0;
 
     loop {
         // Ctrl+C?
-        match cancel_rx.try_recv() {
-            Ok(()) | Err(tokio::sync::oneshot::error::TryRecvError::Closed) => {
-                pb.abandon_with_message("Paused");
-                finalize_sync(file).await?;
-                let s = dlstate::DlState {
-                    url: url.to_string(),
-                    filename: filename.to_string(),
-                    total: total_size,
-                    written,
-                    etag: etag.map(|s| s.to_string()),
-                    last_modified: last_modified.map(|s| s.to_string()),
-                };
-                let _ = dlstate::save_state(&s).await;
-                return Err(DmError::Other("cancelled".into()));
-            }
-            Err(tokio::sync::oneshot::error::TryRecvError::Empty) => { /* keep streaming */ }
+        if opts.cancel.is_cancelled() {
+            pb.abandon_with_message("Paused");
+            finalize_sync(file).await?;
+            let _ = save_single_state(state).await;
+            return Err(DmError::Other("cancelled".into()));
         }
 
         match stream.next().await {
             Some(Ok(chunk)) => {
+                if fresh {
+                    if let Err(e) = page::check_first_chunk(meta, &url, &chunk) {
+                        pb.abandon_with_message("Not the file");
+                        return Err(e);
+                    }
+                    opts.note_head(&chunk);
+                    file.set_len(0).await.map_err(DmError::Io)?;
+                    fresh = false;
+                }
+                opts.throttle(chunk.len() as u64).await;
                 file.write_all(&chunk).await.map_err(DmError::Io)?;
-                written += chunk.len() as u64;
-                pb.set_position(written);
+                if let Some(h) = hasher.as_deref_mut() {
+                    h.update(&chunk);
+                }
+                state.written += chunk.len() as u64;
+                pb.set_position(state.written);
 
-                maybe_save_state(
-                    &mut last_state_dump, written, total_size, url, filename, etag, last_modified
-                ).await;
+                maybe_save_state(&mut last_state_dump, state).await;
             }
             Some(Err(e)) => {
                 pb.abandon_with_message("Retrying…");
                 finalize_sync(file).await?;
-                let s = dlstate::DlState {
-                    url: url.to_string(),
-                    filename: filename.to_string(),
-                    total: total_size,
-                    written,
-                    etag: etag.map(|s| s.to_string()),
-                    last_modified: last_modified.map(|s| s.to_string()),
-                };
-                let _ = dlstate::save_state(&s).await;
-                return Err(DmError::Network(e.to_string()));
+                let _ = save_single_state(state).await;
+                return Err(stream_error(e, expected, state.written));
             }
             None => {
-                pb.finish_with_message("Done");
+                if fresh {
+                    file.set_len(0).await.map_err(DmError::Io)?;
+                }
                 finalize_sync(file).await?;
+                // the server closed early: keep progress and let the retry path resume
+                if state.written < expected {
+                    pb.abandon_with_message("Truncated");
+                    let _ = save_single_state(state).await;
+                    return Err(DmError::Truncated { expected, got: state.written });
+                }
+                pb.finish_with_message("Done");
                 return Ok(());
             }
         }
     }
 }
 
+/// stream_error: a body that breaks off before `expected` is a short read
+/// (hyper reports Content-Length violations this way); anything else is network.
+pub(crate) fn stream_error(e: reqwest::Error, expected: u64, got: u64) -> DmError {
+    if (e.is_body() || e.is_decode()) && got < expected {
+        DmError::Truncated { expected, got }
+    } else {
+        DmError::Network(e.to_string())
+    }
+}
+
 /// Save `.state` about every ~1 MiB written.
-async fn maybe_save_state(
-    last_state_dump: &mut u64,
-    now_written: u64,
-    total_size: Option<u64>,
-    url: &str,
-    filename: &str,
-    etag: Option<&str>,
-    last_modified: Option<&str>,
-) {
-    if now_written >= *last_state_dump + 1_048_576 {
-        *last_state_dump = now_written;
-        let s = dlstate::DlState {
-            url: url.to_string(),
-            filename: filename.to_string(),
-            total: total_size,
-            written: now_written,
-            etag: etag.map(|s| s.to_string()),
-            last_modified: last_modified.map(|s| s.to_string()),
-        };
-        let _ = dlstate::save_state(&s).await;
+async fn maybe_save_state(last_state_dump: &mut u64, state: &mut dlstate::DlState) {
+    if state.written >= *last_state_dump + 1_048_576 {
+        *last_state_dump = state.written;
+        let _ = save_single_state(state).await;
     }
 }
+
+/// save_single_state: a single stream is one segment `[0, total)`; keep it in
+/// step with `written` so a later resume may split the rest across connections.
+async fn save_single_state(state: &mut dlstate::DlState) -> Result<()> {
+    state.segments = match state.total {
+        Some(total) if total > 0 => vec![dlstate::SegmentState {
+            start: 0,
+            end: total - 1,
+            pos: state.written.min(total),
+        }],
+        _ => Vec::new(),
+    };
+    dlstate::save_state(state).await
+}
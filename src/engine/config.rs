@@ -1,9 +1,18 @@
-//! Config: تعریف و ۳ تابع (default/load/save)
+//! Config: تعریف، مسیر فایل، load/save و override از env
+//!
+//! Precedence: default ← config.toml ← TONDAR_* env ← CLI flags
 
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use std::{env, fs, path::Path};
 
+use crate::engine::consts::{MAX_ATTEMPTS, RETRY_DEADLINE_SECS};
+use crate::engine::prelude::*;
+use crate::engine::types::OnConflict;
+use crate::util::rate::parse_rate;
+
+/// A misspelt key (`max_concurent = 9`) is an error, not a silent default.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub max_concurrent: usize,
     pub per_host_limit: usize,
@@ -12,8 +21,57 @@ pub struct Config {
     pub resume: bool,
     pub preallocate: bool,
     pub output_dir: String,
+    /// look for sidecar checksum files (file.sha256, SHA256SUMS, …) next to each link
+    pub find_checksum: bool,
+    /// proxy for every request (http://, https://, socks5://, socks5h://; may carry user:pass@)
+    pub proxy: Option<String>,
+    /// hosts that bypass the proxy, comma separated (overrides the NO_PROXY env var)
+    pub no_proxy: Option<String>,
+    /// per-host overrides, checked first: `[[proxy_rules]] host = "*.corp" proxy = "direct"`
+    pub proxy_rules: Vec<ProxyRule>,
+    /// keep cookies between runs in cookies.txt next to this config file (the profile jar)
+    pub keep_cookies: bool,
+    /// tries per stall of a request, the first one included
+    pub max_attempts: u32,
+    /// seconds to keep retrying one stall before giving up (0 = no limit)
+    pub retry_deadline: u64,
+    /// target name already in use: resume, rename, skip, overwrite or ask
+    pub on_conflict: OnConflict,
+}
+
+/// ProxyRule: requests to `host` (exact, `.suffix` or `*.suffix`) go through `proxy`
+/// ("direct" = no proxy)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRule {
+    pub host: String,
+    pub proxy: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        default_config()
+    }
 }
 
+/// Keys accepted by `config set` and as TONDAR_<KEY> env vars.
+pub const CONFIG_KEYS: &[&str] = &[
+    "max_concurrent",
+    "per_host_limit",
+    "rate_limit_global",
+    "default_parts",
+    "resume",
+    "preallocate",
+    "output_dir",
+    "find_checksum",
+    "proxy",
+    "no_proxy",
+    "keep_cookies",
+    "max_attempts",
+    "retry_deadline",
+    "on_conflict",
+];
+
 /// default_config: تنظیمات پیش‌فرض را برمی‌گرداند.
 pub fn default_config() -> Config {
     Config {
@@ -24,17 +82,46 @@ pub fn default_config() -> Config {
         resume: true,
         preallocate: true,
         output_dir: String::from("./Downloads"),
+        find_checksum: false,
+        proxy: None,
+        no_proxy: None,
+        proxy_rules: Vec::new(),
+        keep_cookies: true,
+        max_attempts: MAX_ATTEMPTS,
+        retry_deadline: RETRY_DEADLINE_SECS,
+        on_conflict: OnConflict::Resume,
     }
 }
 
-/// load_config: کانفیگ را از مسیر بدهٔ TOML می‌خواند (اگر نبود → پیش‌فرض).
-pub fn load_config(path: &str) -> Config {
-    if Path::new(path).exists() {
-        if let Ok(txt) = fs::read_to_string(path) {
-            if let Ok(cfg) = toml::from_str::<Config>(&txt) { return cfg; }
-        }
+/// config_path: `--config` if given, else $XDG_CONFIG_HOME/tondar/config.toml
+/// (falling back to ~/.config/tondar/config.toml).
+pub fn config_path(override_path: Option<&str>) -> String {
+    if let Some(p) = override_path {
+        return p.to_string();
     }
-    default_config()
+    let base = env::var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| env::var("HOME").ok().map(|h| format!("{h}/.config")))
+        .unwrap_or_else(|| ".".to_string());
+    format!("{base}/tondar/config.toml")
+}
+
+/// profile_file: a file of the profile (`cookies.txt`, `queue.txt`), kept beside its
+/// config file — so `--config other.toml` also means other cookies and another queue.
+pub fn profile_file(config_path: &str, name: &str) -> String {
+    let dir = Path::new(config_path).parent().unwrap_or(Path::new("."));
+    dir.join(name).to_string_lossy().into_owned()
+}
+
+/// load_config: کانفیگ را از مسیر دادهٔ TOML می‌خواند (اگر نبود → پیش‌فرض).
+/// A file that exists but does not parse is an error, not silently ignored.
+pub fn load_config(path: &str) -> Result<Config> {
+    if !Path::new(path).exists() {
+        return Ok(default_config());
+    }
+    let txt = fs::read_to_string(path)?;
+    toml::from_str::<Config>(&txt).map_err(|e| DmError::Config(format!("{path}: {e}")))
 }
 
 /// save_config: کانفیگ فعلی را به TOML ذخیره می‌کند.
@@ -43,3 +130,107 @@ pub fn save_config(path: &str, cfg: &Config) -> std::io::Result<()> {
     fs::create_dir_all(Path::new(path).parent().unwrap_or(Path::new(".")))?;
     fs::write(path, s)
 }
+
+/// apply_env: override fields from TONDAR_<KEY> variables (e.g. TONDAR_OUTPUT_DIR).
+pub fn apply_env(cfg: &mut Config) -> Result<()> {
+    for key in CONFIG_KEYS {
+        let var = format!("TONDAR_{}", key.to_ascii_uppercase());
+        if let Ok(v) = env::var(&var) {
+            set_key(cfg, key, &v).map_err(|e| match e {
+                DmError::Config(msg) => DmError::Config(format!("{var}: {msg}")),
+                other => other,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// set_key: set one field from its string form (shared by env and `config set`).
+/// An empty value clears optional fields.
+pub fn set_key(cfg: &mut Config, key: &str, value: &str) -> Result<()> {
+    let v = value.trim();
+    match key {
+        "max_concurrent" => cfg.max_concurrent = parse_num(key, v)?,
+        "per_host_limit" => cfg.per_host_limit = parse_num(key, v)?,
+        "default_parts" => cfg.default_parts = parse_num(key, v)?,
+        "rate_limit_global" => {
+            if !v.is_empty() && parse_rate(v).is_none() {
+                return Err(DmError::Config(format!("{key}: expected e.g. 512K or 2M, got {v:?}")));
+            }
+            cfg.rate_limit_global = (!v.is_empty()).then(|| v.to_string());
+        }
+        "resume" => cfg.resume = parse_bool(key, v)?,
+        "preallocate" => cfg.preallocate = parse_bool(key, v)?,
+        "output_dir" => cfg.output_dir = v.to_string(),
+        "find_checksum" => cfg.find_checksum = parse_bool(key, v)?,
+        "proxy" => cfg.proxy = (!v.is_empty()).then(|| v.to_string()),
+        "no_proxy" => cfg.no_proxy = (!v.is_empty()).then(|| v.to_string()),
+        "keep_cookies" => cfg.keep_cookies = parse_bool(key, v)?,
+        "max_attempts" => cfg.max_attempts = parse_num::<u32>(key, v)?.max(1),
+        "retry_deadline" => cfg.retry_deadline = parse_num(key, v)?,
+        "on_conflict" => cfg.on_conflict = v.parse().map_err(|e| DmError::Config(format!("{key}: {e}")))?,
+        _ => {
+            return Err(DmError::Config(format!(
+                "unknown key {key:?} (known: {})",
+                CONFIG_KEYS.join(", ")
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// parse_num: a whole number that fits the field; "99999999999" for a u32 is an error
+fn parse_num<T: std::str::FromStr<Err = std::num::ParseIntError>>(key: &str, v: &str) -> Result<T> {
+    v.parse()
+        .map_err(|e| DmError::Config(format!("{key}: expected a number, got {v:?} ({e})")))
+}
+
+fn parse_bool(key: &str, v: &str) -> Result<bool> {
+    match v.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(DmError::Config(format!("{key}: expected true/false, got {v:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(text: &str) -> Result<Config> {
+        let path = env::temp_dir().join(format!("tondar-config-{}-{}.toml", std::process::id(), text.len()));
+        fs::write(&path, text).unwrap();
+        let cfg = load_config(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        cfg
+    }
+
+    #[test]
+    fn misspelt_keys_are_errors() {
+        let err = load("max_concurent = 9\n").unwrap_err().to_string();
+        assert!(err.contains("max_concurent"), "{err}");
+        let err = load("[[proxy_rules]]\nhost = \"*.corp\"\nproxi = \"direct\"\n").unwrap_err().to_string();
+        assert!(err.contains("proxi"), "{err}");
+
+        let cfg = load("max_concurrent = 9\n[[proxy_rules]]\nhost = \"*.corp\"\nproxy = \"direct\"\n").unwrap();
+        assert_eq!(cfg.max_concurrent, 9);
+        assert_eq!(cfg.proxy_rules.len(), 1);
+        assert_eq!(cfg.default_parts, default_config().default_parts);
+    }
+
+    #[test]
+    fn set_key_checks_ranges() {
+        let mut cfg = default_config();
+        set_key(&mut cfg, "max_attempts", "0").unwrap();
+        assert_eq!(cfg.max_attempts, 1);
+        set_key(&mut cfg, "max_attempts", "4294967295").unwrap();
+        assert_eq!(cfg.max_attempts, u32::MAX);
+        let err = set_key(&mut cfg, "max_attempts", "4294967296").unwrap_err();
+        assert!(matches!(err, DmError::Config(ref m) if m.starts_with("max_attempts")), "{err}");
+        assert_eq!(cfg.max_attempts, u32::MAX);
+        assert!(set_key(&mut cfg, "max_concurrent", "-1").is_err());
+        assert!(set_key(&mut cfg, "rate_limit_global", "fast").is_err());
+        assert!(set_key(&mut cfg, "resume", "maybe").is_err());
+        assert!(set_key(&mut cfg, "max_concurent", "9").is_err());
+    }
+}
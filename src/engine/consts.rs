@@ -6,3 +6,22 @@ pub const MAX_REDIRECTS: usize = 10;
 // ثانیه
 pub const CONN_TIMEOUT_SECS: u64 = 20;
 pub const REQ_TIMEOUT_SECS:  u64 = 300;
+
+// بایت — کوچک‌ترین تکه‌ای که work stealing از یک segment جدا می‌کند
+pub const MIN_SPLIT_SIZE: u64 = 1_048_576;
+
+// پسوند فایل موقت تا پایان دانلود (بعد rename به نام نهایی)
+pub const PART_SUFFIX: &str = ".tondar.part";
+
+// چند منبعی (mirror): منبعی که این‌قدر خطا پشت سر هم بدهد (بدون داده در این بین) کنار گذاشته می‌شود
+pub const MAX_SOURCE_ERRORS: u32 = 2;
+// منبعی که چند ثانیهٔ پیاپی کندتر از (بهترین ÷ این عدد) باشد کنار گذاشته می‌شود
+pub const SLOW_SOURCE_FACTOR: u64 = 4;
+pub const SLOW_SOURCE_SECS: u32 = 5;
+
+// retry: پیش‌فرض تعداد تلاش و مهلت کل (ثانیه) — config `max_attempts` / `retry_deadline`
+pub const MAX_ATTEMPTS: u32 = 5;
+pub const RETRY_DEADLINE_SECS: u64 = 300;
+// backoff نمایی: 1s, 2s, 4s … تا سقف، با jitter
+pub const RETRY_BASE_MS: u64 = 1_000;
+pub const RETRY_MAX_DELAY_SECS: u64 = 60;
@@ -8,8 +8,27 @@ pub enum DmError {
     Network(String),
     #[error("HTTP status: {0}")]
     HttpStatus(String),
+    /// 429/503…: worth another try, after `retry_after` if the server said so
+    #[error("HTTP status: {msg}")]
+    HttpRetry { msg: String, retry_after: Option<std::time::Duration> },
+    /// the link stopped serving what was probed (expired token, 404, 200 to a range…)
+    #[error("Remote changed: {0}")]
+    Stale(String),
     #[error("IO: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Truncated download: expected {expected} bytes, got {got}")]
+    Truncated { expected: u64, got: u64 },
+    #[error("Checksum mismatch ({algo}): expected {expected}, got {actual}")]
+    ChecksumMismatch { algo: String, expected: String, actual: String },
+    #[error("Piece {piece} failed verification ({algo}): expected {expected}, got {actual}")]
+    PieceMismatch { piece: usize, algo: String, expected: String, actual: String },
+    #[error("Not enough disk space: need {needed} bytes, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    /// an HTML page (login, expired link…) or a tiny reply where the file should be
+    #[error("{url} sent {what} instead of the file (expired link or login required?)")]
+    WrongContent { url: String, what: String },
+    #[error("Config: {0}")]
+    Config(String),
     #[error("Other: {0}")]
     Other(String),
 }
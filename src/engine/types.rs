@@ -1,5 +1,18 @@
 //! انواع دادهٔ مشترک (Meta، Range، JobId…)
 
+use crate::engine::consts::PART_SUFFIX;
+use crate::http::auth::Auth;
+use crate::net::inspect::MetaInfo;
+use crate::net::retry::RetryPolicy;
+use crate::util::checksum::{Checksum, Pieces};
+use crate::util::{filename, mime};
+use crate::util::rate::RateLimiter;
+use indicatif::MultiProgress;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
 #[derive(Debug, Clone)]
 pub struct Meta {
     pub filename: String,
@@ -25,3 +38,142 @@ pub struct PathPlan {
     pub final_name: String,
     pub temp_name: String,
 }
+
+impl PathPlan {
+    /// new: downloads land in `dir/<final_name>.tondar.part` until complete.
+    /// The name is sanitized here too, so no caller can step outside `dir`.
+    pub fn new(dir: &str, final_name: &str) -> Self {
+        let final_name = filename::sanitize(final_name);
+        Self {
+            dir: dir.to_string(),
+            temp_name: format!("{final_name}{PART_SUFFIX}"),
+            final_name,
+        }
+    }
+
+    /// from_temp_path: rebuild the plan from a `.part` path (e.g. found via a .state file).
+    /// Paths without the suffix are treated as writing in place.
+    pub fn from_temp_path(temp: &str) -> Option<Self> {
+        let p = Path::new(temp);
+        let temp_name = p.file_name()?.to_string_lossy().into_owned();
+        let dir = p.parent().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default();
+        let final_name = temp_name.strip_suffix(PART_SUFFIX).unwrap_or(&temp_name).to_string();
+        Some(Self { dir, final_name, temp_name })
+    }
+
+    pub fn final_path(&self) -> String {
+        Path::new(&self.dir).join(&self.final_name).to_string_lossy().into_owned()
+    }
+
+    pub fn temp_path(&self) -> String {
+        Path::new(&self.dir).join(&self.temp_name).to_string_lossy().into_owned()
+    }
+
+    /// numbered: the same plan saved as `name (n).ext`
+    pub fn numbered(&self, n: u32) -> Self {
+        PathPlan::new(&self.dir, &filename::numbered(&self.final_name, n))
+    }
+}
+
+/// OnConflict: what to do when the target name is already in use (`--on-conflict`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// continue a .part whose .state has the same URL and validators; anything else → rename
+    #[default]
+    Resume,
+    /// save as `name (1).ext`, `name (2).ext`, …
+    Rename,
+    /// leave the existing file alone and do not download
+    Skip,
+    /// start from zero and replace the existing file when done
+    Overwrite,
+    /// ask for each file (on a terminal; otherwise like `resume`)
+    Ask,
+}
+
+impl OnConflict {
+    pub const NAMES: &'static str = "resume, rename, skip, overwrite, ask";
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "resume" => Ok(OnConflict::Resume),
+            "rename" => Ok(OnConflict::Rename),
+            "skip" => Ok(OnConflict::Skip),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "ask" => Ok(OnConflict::Ask),
+            other => Err(format!("expected one of {}, got {other:?}", OnConflict::NAMES)),
+        }
+    }
+}
+
+/// CancelToken: پرچم لغو مشترک بین تسک‌ها (Ctrl+C، توقف، لغو)
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// DlOpts: تنظیمات هر دانلود که بین موتور single و multi مشترک است
+#[derive(Debug, Clone, Default)]
+pub struct DlOpts {
+    /// number of connections (≤1 → single stream)
+    pub parts: usize,
+    /// send If-Range (ETag/Last-Modified) on ranged requests
+    pub if_range: bool,
+    /// continue into existing data (false → always start from zero)
+    pub resume: bool,
+    /// reserve the full size on disk before writing (segmented downloads)
+    pub preallocate: bool,
+    pub cancel: CancelToken,
+    /// bars of concurrent jobs are drawn together
+    pub progress: MultiProgress,
+    /// global limiter (shared by all jobs) and/or a per-job cap
+    pub limiters: Vec<RateLimiter>,
+    /// expected digest; a mismatch keeps the .part file instead of renaming it
+    pub checksum: Option<Checksum>,
+    /// per-piece digests (metalink); bad pieces are fetched again before the rename
+    pub pieces: Option<Arc<Pieces>>,
+    /// more sources for the same file (probed, same size/ETag); segments are
+    /// spread over them and the first URL together
+    pub sources: Vec<MetaInfo>,
+    /// credentials for every request of the job
+    pub auth: Arc<Auth>,
+    /// attempt cap and deadline for failed requests
+    pub retry: RetryPolicy,
+    /// extra request headers the client sends ("Key: Value"); kept in the .state
+    pub headers: Vec<String>,
+    /// what the first bytes of the file said it is (MIME type, fitting extensions),
+    /// noted as they stream in; fresh for every download_multi call
+    pub sniffed: Arc<OnceLock<(&'static str, &'static [&'static str])>>,
+}
+
+impl DlOpts {
+    /// note_head: remember the type the chunk at offset 0 shows (magic numbers)
+    pub fn note_head(&self, chunk: &[u8]) {
+        if let Some(found) = mime::sniff(chunk) {
+            let _ = self.sniffed.set(found);
+        }
+    }
+
+    /// throttle: wait until every limiter allows `n` more bytes
+    pub async fn throttle(&self, n: u64) {
+        for l in &self.limiters {
+            l.acquire(n).await;
+        }
+    }
+}
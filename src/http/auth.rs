@@ -0,0 +1,353 @@
+//! HTTP authentication: Basic, Digest, Bearer and ~/.netrc
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - Bearer tokens are sent up front; Basic/Digest only answer a 401 challenge
+//!   (then the scheme is remembered per host, so ranged GETs skip the extra round trip)
+//! - `--user/--password/--bearer` only go to hosts the user named (`allow`); any other
+//!   host, e.g. the target of a redirect, only gets its own ~/.netrc entry (reqwest
+//!   itself drops Authorization on cross-host redirects)
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Request, RequestBuilder, Response, StatusCode};
+use base64::Engine as _;
+use digest_auth::{AuthContext, HttpMethod, WwwAuthenticateHeader};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+use url::Url;
+
+/// Scheme a host asked for, remembered after its first 401
+enum Scheme {
+    Basic,
+    Digest(Box<WwwAuthenticateHeader>),
+}
+
+#[derive(Default)]
+pub struct Auth {
+    user: Option<(String, String)>,
+    bearer: Option<String>,
+    netrc: Netrc,
+    /// `host:port`s the explicit credentials may be sent to
+    hosts: Mutex<HashSet<String>>,
+    schemes: Mutex<HashMap<String, Scheme>>,
+}
+
+impl fmt::Debug for Auth {
+    // never print secrets
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("user", &self.user.as_ref().map(|(u, _)| u))
+            .field("bearer", &self.bearer.as_ref().map(|_| "***"))
+            .field("netrc_hosts", &self.netrc.machines.len())
+            .finish()
+    }
+}
+
+impl Auth {
+    /// new: explicit credentials plus ~/.netrc (or $NETRC). `user` may be `name:password`.
+    pub fn new(user: Option<&str>, password: Option<&str>, bearer: Option<&str>) -> Self {
+        let user = user.map(|u| match (u.split_once(':'), password) {
+            (_, Some(p)) => (u.to_string(), p.to_string()),
+            (Some((name, p)), None) => (name.to_string(), p.to_string()),
+            (None, None) => (u.to_string(), String::new()),
+        });
+        Auth { user, bearer: bearer.map(str::to_string), netrc: Netrc::load(), ..Default::default() }
+    }
+
+    /// allow: let the explicit credentials go to `url`'s host
+    pub fn allow(&self, url: &str) {
+        if let Ok(u) = Url::parse(url) {
+            self.hosts.lock().unwrap().insert(host_key(&u));
+        }
+    }
+
+    /// send: execute `req`, answering one Basic/Digest challenge if the server sends 401
+    pub async fn send(&self, req: RequestBuilder) -> reqwest::Result<Response> {
+        let (client, req) = req.build_split();
+        let mut req = req?;
+        self.authorize(&mut req);
+
+        let retry = req.try_clone();
+        let resp = client.execute(req).await?;
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+        let Some(mut retry) = retry else { return Ok(resp) };
+
+        // answer for the URL that challenged us (may be after a redirect)
+        let url = resp.url().clone();
+        let host = host_key(&url);
+        let Some(scheme) = challenge(resp.headers()) else { return Ok(resp) };
+        if self.creds_for(&host).is_none() {
+            return Ok(resp);
+        }
+        self.schemes.lock().unwrap().insert(host, scheme);
+        *retry.url_mut() = url;
+        retry.headers_mut().remove(AUTHORIZATION);
+        self.authorize(&mut retry);
+        client.execute(retry).await
+    }
+
+    /// authorize: add an Authorization header if we know how to for this host
+    fn authorize(&self, req: &mut Request) {
+        let host = host_key(req.url());
+        if let Some(token) = &self.bearer {
+            if self.allowed(&host) {
+                if let Ok(v) = HeaderValue::from_str(&format!("Bearer {token}")) {
+                    req.headers_mut().insert(AUTHORIZATION, v);
+                }
+                return;
+            }
+        }
+        let Some((user, pass)) = self.creds_for(&host) else { return };
+        let value = match self.schemes.lock().unwrap().get_mut(&host) {
+            None => return,
+            Some(Scheme::Basic) => {
+                let raw = format!("{user}:{pass}");
+                format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(raw))
+            }
+            Some(Scheme::Digest(prompt)) => {
+                let uri = match req.url().query() {
+                    Some(q) => format!("{}?{q}", req.url().path()),
+                    None => req.url().path().to_string(),
+                };
+                let ctx = AuthContext::new_with_method(
+                    user,
+                    pass,
+                    uri,
+                    None::<&[u8]>,
+                    HttpMethod::from(req.method().as_str().to_string()),
+                );
+                match prompt.respond(&ctx) {
+                    Ok(h) => h.to_header_string(),
+                    Err(_) => return,
+                }
+            }
+        };
+        if let Ok(v) = HeaderValue::from_str(&value) {
+            req.headers_mut().insert(AUTHORIZATION, v);
+        }
+    }
+
+    /// creds_for: explicit credentials on an allowed host, else the host's netrc entry
+    fn creds_for(&self, host: &str) -> Option<(String, String)> {
+        match &self.user {
+            Some(c) if self.allowed(host) => Some(c.clone()),
+            _ => self.netrc.lookup(host.rsplit_once(':').map_or(host, |(h, _)| h)),
+        }
+    }
+
+    fn allowed(&self, host: &str) -> bool {
+        self.hosts.lock().unwrap().contains(host)
+    }
+}
+
+/// challenge: strongest scheme offered in WWW-Authenticate (Digest over Basic)
+fn challenge(headers: &HeaderMap) -> Option<Scheme> {
+    let offers: Vec<&str> = headers
+        .get_all(WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    let digest = offers
+        .iter()
+        .filter(|v| v.len() > 7 && v[..7].eq_ignore_ascii_case("digest "))
+        .find_map(|v| digest_auth::parse(v).ok());
+    match digest {
+        Some(d) => Some(Scheme::Digest(Box::new(d))),
+        None => offers
+            .iter()
+            .any(|v| v.len() >= 5 && v[..5].eq_ignore_ascii_case("basic"))
+            .then_some(Scheme::Basic),
+    }
+}
+
+/// host_key: `host:port` — credentials never cross to another origin
+fn host_key(url: &Url) -> String {
+    format!("{}:{}", url.host_str().unwrap_or(""), url.port_or_known_default().unwrap_or(0))
+}
+
+/// Netrc: `machine <host> login <name> password <secret>` entries plus `default`
+#[derive(Debug, Default)]
+struct Netrc {
+    machines: HashMap<String, (String, String)>,
+    default: Option<(String, String)>,
+}
+
+impl Netrc {
+    /// load: $NETRC, else ~/.netrc; a missing file means no entries
+    fn load() -> Self {
+        let path = std::env::var("NETRC")
+            .ok()
+            .or_else(|| std::env::var("HOME").ok().map(|h| format!("{h}/.netrc")));
+        path.and_then(|p| std::fs::read_to_string(p).ok())
+            .map(|s| Self::parse(&s))
+            .unwrap_or_default()
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut out = Netrc::default();
+        // macro bodies (`macdef name` up to an empty line) and comments are skipped
+        let mut words = Vec::new();
+        let mut in_macro = false;
+        for line in text.lines() {
+            if in_macro {
+                in_macro = !line.trim().is_empty();
+                continue;
+            }
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            for w in line.split_whitespace() {
+                if w == "macdef" {
+                    in_macro = true;
+                    break;
+                }
+                words.push(w);
+            }
+        }
+
+        let mut tokens = words.into_iter();
+        // (machine or None for `default`, login, password)
+        let mut cur: Option<(Option<String>, String, String)> = None;
+        let mut flush = |cur: &mut Option<(Option<String>, String, String)>| match cur.take() {
+            Some((Some(host), login, pass)) => {
+                out.machines.entry(host).or_insert((login, pass));
+            }
+            Some((None, login, pass)) => out.default = Some((login, pass)),
+            None => {}
+        };
+        while let Some(tok) = tokens.next() {
+            match tok {
+                "machine" => {
+                    flush(&mut cur);
+                    cur = tokens.next().map(|h| (Some(h.to_ascii_lowercase()), String::new(), String::new()));
+                }
+                "default" => {
+                    flush(&mut cur);
+                    cur = Some((None, String::new(), String::new()));
+                }
+                "login" => {
+                    if let (Some(c), Some(v)) = (cur.as_mut(), tokens.next()) {
+                        c.1 = v.to_string();
+                    }
+                }
+                "password" => {
+                    if let (Some(c), Some(v)) = (cur.as_mut(), tokens.next()) {
+                        c.2 = v.to_string();
+                    }
+                }
+                "account" => {
+                    tokens.next();
+                }
+                _ => {}
+            }
+        }
+        flush(&mut cur);
+        out
+    }
+
+    fn lookup(&self, host: &str) -> Option<(String, String)> {
+        self.machines.get(&host.to_ascii_lowercase()).or(self.default.as_ref()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Method;
+
+    const NETRC: &str = "\
+# comment
+machine files.example login alice password s3cret
+machine Other.Example
+  login bob
+  password hunter2
+  account ignored
+
+macdef init
+machine evil.example login mallory password nope
+
+default login anonymous password guest@
+";
+
+    fn request(auth: &Auth, url: &str) -> Option<String> {
+        let mut req = Request::new(Method::GET, Url::parse(url).unwrap());
+        auth.authorize(&mut req);
+        req.headers().get(AUTHORIZATION).map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn netrc_machines_default_and_macdef() {
+        let n = Netrc::parse(NETRC);
+        assert_eq!(n.lookup("files.example"), Some(("alice".into(), "s3cret".into())));
+        assert_eq!(n.lookup("OTHER.example"), Some(("bob".into(), "hunter2".into())));
+        // the macro body is not an entry; unknown hosts get `default`
+        assert_eq!(n.lookup("evil.example"), Some(("anonymous".into(), "guest@".into())));
+        assert_eq!(n.machines.len(), 2);
+        assert!(Netrc::parse("machine a.example login x password y\n").lookup("b.example").is_none());
+    }
+
+    #[test]
+    fn netrc_first_machine_entry_wins() {
+        let n = Netrc::parse("machine a.example login one password 1\nmachine a.example login two password 2\n");
+        assert_eq!(n.lookup("a.example"), Some(("one".into(), "1".into())));
+    }
+
+    #[test]
+    fn digest_challenge_with_qop_and_opaque() {
+        let mut h = HeaderMap::new();
+        h.append(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"files\""));
+        h.append(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static(
+                "Digest realm=\"files@example\", qop=\"auth,auth-int\", nonce=\"dcd98b7102dd2f0e\", opaque=\"5ccc069c403ebaf9\"",
+            ),
+        );
+        let Some(Scheme::Digest(d)) = challenge(&h) else { panic!("expected Digest") };
+        assert_eq!((d.realm.as_str(), d.nonce.as_str()), ("files@example", "dcd98b7102dd2f0e"));
+        assert_eq!(d.opaque.as_deref(), Some("5ccc069c403ebaf9"));
+        assert_eq!(d.qop.as_ref().map(Vec::len), Some(2));
+
+        let mut h = HeaderMap::new();
+        h.append(WWW_AUTHENTICATE, HeaderValue::from_static("basic realm=\"x\""));
+        assert!(matches!(challenge(&h), Some(Scheme::Basic)));
+        assert!(challenge(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn explicit_credentials_stay_on_the_allowed_origin() {
+        let auth = Auth { user: Some(("me".into(), "pw".into())), netrc: Netrc::parse(NETRC), ..Default::default() };
+        auth.allow("https://dl.example/file.iso");
+        for host in ["dl.example:443", "dl.example:8443", "cdn.example:443", "files.example:443"] {
+            auth.schemes.lock().unwrap().insert(host.to_string(), Scheme::Basic);
+        }
+        let basic = |u: &str, p: &str| format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{u}:{p}")));
+
+        assert_eq!(request(&auth, "https://dl.example/file.iso"), Some(basic("me", "pw")));
+        // another port or host only gets its own netrc entry (here: `default`)
+        assert_eq!(request(&auth, "https://dl.example:8443/file.iso"), Some(basic("anonymous", "guest@")));
+        assert_eq!(request(&auth, "https://cdn.example/file.iso"), Some(basic("anonymous", "guest@")));
+        assert_eq!(request(&auth, "https://files.example/file.iso"), Some(basic("alice", "s3cret")));
+        // no challenge seen from a host: nothing is sent up front
+        assert_eq!(request(&auth, "https://new.example/file.iso"), None);
+    }
+
+    #[test]
+    fn bearer_only_to_allowed_origin() {
+        let auth = Auth { bearer: Some("tok".into()), ..Default::default() };
+        auth.allow("https://api.example/v1/file");
+        assert_eq!(request(&auth, "https://api.example/other").as_deref(), Some("Bearer tok"));
+        assert_eq!(request(&auth, "http://api.example/other"), None);
+        assert_eq!(request(&auth, "https://api.example:444/other"), None);
+        assert_eq!(request(&auth, "https://cdn.example/other"), None);
+    }
+
+    #[test]
+    fn user_may_carry_the_password() {
+        let auth = Auth { netrc: Netrc::default(), ..Auth::new(Some("me:pw:with:colons"), None, None) };
+        assert_eq!(auth.user, Some(("me".into(), "pw:with:colons".into())));
+        let auth = Auth { netrc: Netrc::default(), ..Auth::new(Some("me:x"), Some("real"), None) };
+        assert_eq!(auth.user, Some(("me:x".into(), "real".into())));
+    }
+}
@@ -7,27 +7,40 @@
 
 //! HTTP client + light probes
 use reqwest::{Client, Response, redirect::Policy};
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, ACCEPT_ENCODING, REFERER, COOKIE};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, REFERER};
+use std::sync::Arc;
 use std::time::Duration;
 
 use crate::engine::prelude::*;
 use crate::engine::consts::*;
 
+use crate::http::cookies::CookieJar;
+use crate::http::proxy::Proxies;
 use crate::ui::cli::Args;
 
-pub fn build_client(args: &Args) -> Result<Client> {
+/// build_client: `jar` keeps Set-Cookie across redirects and ranged requests
+/// (and carries --cookie to the hosts the user named);
+/// `headers` are "Key: Value" lines (-H, plus per-entry `header=` of a batch file)
+pub fn build_client(args: &Args, proxies: &Proxies, jar: Arc<CookieJar>, headers: &[String]) -> Result<Client> {
     let mut default_headers = HeaderMap::new();
     default_headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
     default_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, br, deflate, zstd"));
     if let Some(ref r) = args.referer {
         if let Ok(v) = HeaderValue::from_str(r) { default_headers.insert(REFERER, v); }
     }
-    if let Some(ref c) = args.cookie {
-        if let Ok(v) = HeaderValue::from_str(c) { default_headers.insert(COOKIE, v); }
+    for line in headers {
+        let parsed = line.split_once(':').and_then(|(k, v)| {
+            Some((HeaderName::try_from(k.trim()).ok()?, HeaderValue::from_str(v.trim()).ok()?))
+        });
+        match parsed {
+            Some((k, v)) => { default_headers.insert(k, v); }
+            None => return Err(DmError::Config(format!("invalid header {line:?} (expected \"Key: Value\")"))),
+        }
     }
     // UA را با builder ست می‌کنیم (نه هدر دستی)
     let builder = Client::builder()
         .default_headers(default_headers)
+        .cookie_provider(jar)
         .user_agent(args.ua.as_deref().unwrap_or(USER_AGENT))
         .gzip(true).brotli(true).zstd(true)
         .redirect(Policy::limited(MAX_REDIRECTS))
@@ -35,7 +48,7 @@ pub fn build_client(args: &Args) -> Result<Client> {
         .timeout(Duration::from_secs(REQ_TIMEOUT_SECS))
         .use_rustls_tls();
 
-    builder.build().map_err(|e| DmError::Other(e.to_string()))
+    proxies.apply(builder).build().map_err(|e| DmError::Other(e.to_string()))
 }
 
 pub async fn send_head(client: &Client, url: &str) -> Result<Response> {
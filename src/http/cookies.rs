@@ -0,0 +1,327 @@
+//! Cookie jar: Netscape cookies.txt import/export + the per-profile persistent jar
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - Plugged into reqwest as its `CookieStore`, so Set-Cookie from the probe and from
+//!   every redirect hop is kept and sent again on the ranged GETs that follow
+//! - `--cookie` (a raw header line) only goes to the hosts the user named (`allow`),
+//!   ahead of jar cookies; a redirect to another host or a metalink mirror gets
+//!   only the jar's own cookies for it
+//! - `--save-cookies` gets every live cookie; session cookies (no Expires/Max-Age)
+//!   are written with expiry 0, like curl
+//! - The profile jar only keeps persistent cookies that a contacted host set:
+//!   a `--load-cookies` browser export is used for the run, never copied into it,
+//!   and session cookies end with the run instead of living on forever
+
+use cookie::Cookie;
+use reqwest::cookie::CookieStore;
+use reqwest::header::HeaderValue;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use crate::engine::prelude::*;
+
+/// One stored cookie, in the shape of a cookies.txt line
+#[derive(Debug, Clone)]
+struct Entry {
+    /// lowercase, without a leading dot
+    domain: String,
+    /// false = also sent to subdomains (Domain= attribute / TRUE flag)
+    host_only: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// unix seconds; None = session cookie
+    expires: Option<i64>,
+    name: String,
+    value: String,
+    /// came from `--load-cookies` (not set by a server during this or an earlier run)
+    imported: bool,
+}
+
+impl Entry {
+    fn expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|t| t <= now)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    entries: Mutex<Vec<Entry>>,
+    /// `--cookie` line, sent as-is to `raw_hosts`
+    raw: Option<String>,
+    /// lowercase hosts the user named on the command line / in the input list
+    raw_hosts: Mutex<HashSet<String>>,
+}
+
+impl CookieJar {
+    /// new: empty jar; `raw` is the `--cookie` header line
+    pub fn new(raw: Option<&str>) -> Self {
+        CookieJar { raw: raw.map(str::to_string).filter(|s| !s.trim().is_empty()), ..Default::default() }
+    }
+
+    /// allow: let the `--cookie` line go to `url`'s host
+    pub fn allow(&self, url: &str) {
+        if let Some(host) = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_ascii_lowercase)) {
+            self.raw_hosts.lock().unwrap().insert(host);
+        }
+    }
+
+    /// load_profile: merge the profile jar; a missing file is an empty jar
+    pub fn load_profile(&self, path: &str) -> Result<usize> {
+        self.read(path, false)
+    }
+
+    /// import: merge a `--load-cookies` file for this run; returns how many cookies it held
+    pub fn import(&self, path: &str) -> Result<usize> {
+        self.read(path, true)
+    }
+
+    fn read(&self, path: &str, imported: bool) -> Result<usize> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if !imported && e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(DmError::Config(format!("cookies {path}: {e}"))),
+        };
+        let now = now_secs();
+        let mut n = 0;
+        for line in text.lines() {
+            let Some(e) = parse_line(line) else { continue };
+            if e.expired(now) {
+                continue;
+            }
+            self.insert(Entry { imported, ..e });
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// save: write every live cookie as a Netscape cookies.txt (mode 0600 on unix)
+    pub fn save(&self, path: &str) -> Result<()> {
+        self.write(path, |_| true)
+    }
+
+    /// save_profile: the profile jar — persistent cookies set by the hosts we talked to
+    pub fn save_profile(&self, path: &str) -> Result<()> {
+        self.write(path, |e| !e.imported && e.expires.is_some())
+    }
+
+    fn write(&self, path: &str, keep: impl Fn(&Entry) -> bool) -> Result<()> {
+        let now = now_secs();
+        let mut out = String::from("# Netscape HTTP Cookie File\n# Written by TondarDM; edit at your own risk.\n\n");
+        for e in self.entries.lock().unwrap().iter().filter(|e| !e.expired(now) && keep(e)) {
+            let (domain, flag) = match e.host_only {
+                true => (e.domain.clone(), "FALSE"),
+                false => (format!(".{}", e.domain), "TRUE"),
+            };
+            out.push_str(&format!(
+                "{}{domain}\t{flag}\t{}\t{}\t{}\t{}\t{}\n",
+                if e.http_only { "#HttpOnly_" } else { "" },
+                e.path,
+                if e.secure { "TRUE" } else { "FALSE" },
+                e.expires.unwrap_or(0),
+                e.name,
+                e.value,
+            ));
+        }
+        if let Some(dir) = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        // cookies are credentials: the file is private
+        let mut opts = std::fs::OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        use std::io::Write;
+        opts.open(path)?.write_all(out.as_bytes())?;
+        Ok(())
+    }
+
+    /// insert: replace the cookie with the same (domain, path, name), or add it
+    fn insert(&self, e: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|o| !(o.domain == e.domain && o.path == e.path && o.name == e.name));
+        entries.push(e);
+    }
+
+    fn remove(&self, domain: &str, path: &str, name: &str) {
+        self.entries.lock().unwrap().retain(|o| !(o.domain == domain && o.path == path && o.name == name));
+    }
+
+    /// store: one Set-Cookie value received from `url` (RFC 6265 §5.3, minus the public-suffix list)
+    fn store(&self, header: &str, url: &Url) {
+        let Ok(c) = Cookie::parse(header) else { return };
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+
+        let (domain, host_only) = match c.domain().map(|d| d.trim_start_matches('.').to_ascii_lowercase()) {
+            Some(d) if !d.is_empty() => {
+                // a cookie for "com" or for some other site is refused
+                if !d.contains('.') || !domain_matches(&d, &host) {
+                    return;
+                }
+                (d, false)
+            }
+            _ => (host, true),
+        };
+        let path = match c.path() {
+            Some(p) if p.starts_with('/') => p.to_string(),
+            _ => default_path(url),
+        };
+
+        // Max-Age wins over Expires; a date in the past deletes the cookie
+        let expires = match (c.max_age(), c.expires_datetime()) {
+            (Some(age), _) => Some(now_secs() + age.whole_seconds()),
+            (None, Some(at)) => Some(at.unix_timestamp()),
+            (None, None) => None,
+        };
+        if expires.is_some_and(|t| t <= now_secs()) {
+            self.remove(&domain, &path, c.name());
+            return;
+        }
+
+        self.insert(Entry {
+            domain,
+            host_only,
+            path,
+            secure: c.secure().unwrap_or(false),
+            http_only: c.http_only().unwrap_or(false),
+            expires,
+            name: c.name().to_string(),
+            value: c.value().to_string(),
+            imported: false,
+        });
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        for h in cookie_headers {
+            if let Ok(s) = h.to_str() {
+                self.store(s, url);
+            }
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let now = now_secs();
+        let entries = self.entries.lock().unwrap();
+        let mut hits: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| if e.host_only { e.domain == host } else { domain_matches(&e.domain, &host) })
+            .filter(|e| path_matches(&e.path, url.path()))
+            .filter(|e| !e.secure || url.scheme() == "https")
+            .filter(|e| !e.expired(now))
+            .collect();
+        // longer paths first (RFC 6265 §5.4)
+        hits.sort_by_key(|e| std::cmp::Reverse(e.path.len()));
+
+        let mut parts: Vec<String> =
+            self.raw.iter().filter(|_| self.raw_hosts.lock().unwrap().contains(&host)).cloned().collect();
+        parts.extend(hits.iter().map(|e| format!("{}={}", e.name, e.value)));
+        if parts.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&parts.join("; ")).ok()
+    }
+}
+
+/// parse_line: `domain  flag  path  secure  expires  name  value` (tab separated)
+fn parse_line(line: &str) -> Option<Entry> {
+    let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+        Some(rest) => (rest, true),
+        None => (line, false),
+    };
+    if line.trim().is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let f: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+    if f.len() < 7 {
+        return None;
+    }
+    let domain = f[0].trim_start_matches('.').to_ascii_lowercase();
+    let expires: i64 = f[4].trim().parse().ok()?;
+    Some(Entry {
+        host_only: !f[1].eq_ignore_ascii_case("TRUE") && !f[0].starts_with('.'),
+        domain,
+        path: f[2].to_string(),
+        secure: f[3].eq_ignore_ascii_case("TRUE"),
+        http_only,
+        expires: (expires > 0).then_some(expires),
+        name: f[5].to_string(),
+        // a value may itself contain tabs
+        value: f[6..].join("\t"),
+        imported: false,
+    })
+}
+
+fn domain_matches(domain: &str, host: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|rest| rest.ends_with('.'))
+}
+
+fn path_matches(cookie_path: &str, req_path: &str) -> bool {
+    req_path == cookie_path
+        || (req_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || req_path[cookie_path.len()..].starts_with('/')))
+}
+
+/// default_path: the request path up to (not including) its last '/'
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => url.path()[..i].to_string(),
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_keeps_only_persistent_server_cookies() {
+        let dir = std::env::temp_dir().join(format!("tondar-cookies-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let export = dir.join("browser.txt");
+        std::fs::write(&export, "mail.example\tFALSE\t/\tTRUE\t4102444800\tSID\tbrowser\n").unwrap();
+
+        let jar = CookieJar::new(None);
+        assert_eq!(jar.import(export.to_str().unwrap()).unwrap(), 1);
+        let url = Url::parse("https://cdn.example/file.zip").unwrap();
+        jar.store("token=abc; Max-Age=3600", &url);
+        jar.store("session=xyz", &url);
+
+        let (all, profile) = (dir.join("all.txt"), dir.join("profile.txt"));
+        jar.save(all.to_str().unwrap()).unwrap();
+        jar.save_profile(profile.to_str().unwrap()).unwrap();
+        let all = std::fs::read_to_string(all).unwrap();
+        let profile = std::fs::read_to_string(profile).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(all.contains("\tSID\tbrowser") && all.contains("\ttoken\tabc") && all.contains("\tsession\txyz"));
+        assert!(profile.contains("\ttoken\tabc"));
+        assert!(!profile.contains("SID") && !profile.contains("session"));
+    }
+
+    #[test]
+    fn raw_cookie_stays_on_the_named_host() {
+        let jar = CookieJar::new(Some("SID=secret"));
+        jar.allow("http://127.0.0.1:8080/start");
+        let start = Url::parse("http://127.0.0.1:8080/file.zip").unwrap();
+        let redirected = Url::parse("http://localhost:8080/file.zip").unwrap();
+        // the redirect target set a cookie of its own: that one still goes back to it
+        jar.store("cdn=1", &redirected);
+
+        assert_eq!(jar.cookies(&start).unwrap(), "SID=secret");
+        assert_eq!(jar.cookies(&redirected).unwrap(), "cdn=1");
+        assert!(jar.cookies(&Url::parse("https://mirror.example/file.zip").unwrap()).is_none());
+    }
+}
@@ -0,0 +1,240 @@
+//! Proxy selection: --proxy / config / HTTP(S)_PROXY, NO_PROXY and per-host rules
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! Order for each request URL:
+//! 1. first matching `[[proxy_rules]]` entry in config (a proxy URL, or "direct")
+//! 2. NO_PROXY (`--no-proxy`, else config `no_proxy`, else the env var) → direct
+//! 3. `--proxy` / config `proxy` for every scheme
+//! 4. env HTTPS_PROXY / HTTP_PROXY (by scheme), then ALL_PROXY
+//!
+//! Proxy URLs may be http://, https://, socks5:// or socks5h:// and may carry
+//! `user:password@` for proxy authentication.
+
+use reqwest::{ClientBuilder, Proxy};
+use std::env;
+use url::Url;
+
+use crate::engine::config::{Config, ProxyRule};
+use crate::engine::prelude::*;
+
+#[derive(Debug, Clone, Default)]
+pub struct Proxies {
+    /// (host pattern, proxy or None for direct)
+    rules: Vec<(String, Option<Url>)>,
+    no_proxy: Vec<String>,
+    all: Option<Url>,
+    http: Option<Url>,
+    https: Option<Url>,
+}
+
+impl Proxies {
+    /// new: `explicit` is --proxy (falls back to config `proxy`, then env)
+    pub fn new(explicit: Option<&str>, cfg: &Config) -> Result<Self> {
+        Self::with_env(explicit, cfg, &|name| env::var(name).ok())
+    }
+
+    /// with_env: `new` with the environment read through `var`
+    fn with_env(explicit: Option<&str>, cfg: &Config, var: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let env_any = |names: &[&str]| names.iter().find_map(|n| var(n)).filter(|v| !v.trim().is_empty());
+        let mut rules = Vec::new();
+        for ProxyRule { host, proxy } in &cfg.proxy_rules {
+            let target = match proxy.trim() {
+                "direct" | "none" | "" => None,
+                p => Some(parse_proxy(p)?),
+            };
+            rules.push((host.trim().to_ascii_lowercase(), target));
+        }
+
+        let no_proxy = cfg
+            .no_proxy
+            .clone()
+            .or_else(|| env_any(&["NO_PROXY", "no_proxy"]))
+            .map(|s| split_list(&s))
+            .unwrap_or_default();
+
+        let all = match explicit.or(cfg.proxy.as_deref()) {
+            Some(p) => Some(parse_proxy(p)?),
+            None => None,
+        };
+        // env vars are only a fallback; a broken one is ignored like curl does
+        let from_env = |names: &[&str]| env_any(names).and_then(|p| parse_proxy(&p).ok());
+        let (http, https) = match all {
+            Some(_) => (None, None),
+            None => (from_env(&["HTTP_PROXY", "http_proxy"]), from_env(&["HTTPS_PROXY", "https_proxy"])),
+        };
+        let all = all.or_else(|| from_env(&["ALL_PROXY", "all_proxy"]));
+
+        Ok(Proxies { rules, no_proxy, all, http, https })
+    }
+
+    /// for_url: the proxy a request to `url` goes through (None = direct)
+    pub fn for_url(&self, url: &Url) -> Option<&Url> {
+        let host = url.host_str().unwrap_or("").trim_matches(['[', ']']).to_ascii_lowercase();
+        if let Some((_, target)) = self.rules.iter().find(|(pat, _)| host_matches(pat, &host)) {
+            return target.as_ref();
+        }
+        if self.no_proxy.iter().any(|pat| host_matches(pat, &host)) {
+            return None;
+        }
+        let by_scheme = match url.scheme() {
+            "https" => self.https.as_ref(),
+            _ => self.http.as_ref(),
+        };
+        by_scheme.or(self.all.as_ref())
+    }
+
+    /// apply: route the client through `for_url` (and nothing else — no implicit system proxy)
+    pub fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        let this = self.clone();
+        builder.no_proxy().proxy(Proxy::custom(move |url| this.for_url(url).cloned()))
+    }
+}
+
+/// redact: proxy URL for display, without its password
+pub fn redact(url: &Url) -> String {
+    let mut u = url.clone();
+    if u.password().is_some() {
+        let _ = u.set_password(Some("***"));
+    }
+    u.to_string()
+}
+
+fn parse_proxy(s: &str) -> Result<Url> {
+    // "host:3128" is taken as an HTTP proxy, like curl
+    let s = s.trim();
+    let with_scheme = if s.contains("://") { s.to_string() } else { format!("http://{s}") };
+    let url = Url::parse(&with_scheme).map_err(|e| DmError::Config(format!("proxy {s:?}: {e}")))?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(url),
+        other => Err(DmError::Config(format!(
+            "proxy {s:?}: unsupported scheme {other:?} (use http, https, socks5 or socks5h)"
+        ))),
+    }
+}
+
+/// host_matches: `*`, exact host, `.example.com` / `*.example.com` (subdomains),
+/// and `example.com` also covering its subdomains (NO_PROXY convention)
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let p = pattern.trim_start_matches('*');
+    if p.is_empty() {
+        return pattern == "*";
+    }
+    if let Some(suffix) = p.strip_prefix('.') {
+        return host.ends_with(p) || host == suffix;
+    }
+    host == p || host.ends_with(&format!(".{p}"))
+}
+
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|p| p.trim().to_ascii_lowercase())
+        // a port in NO_PROXY is accepted but not used for matching
+        .map(|p| match p.strip_prefix('[').and_then(|v6| v6.split_once(']')) {
+            Some((ip, _)) => ip.to_string(),
+            None => match p.rsplit_once(':') {
+                Some((h, port)) if !h.contains(':') && port.chars().all(|c| c.is_ascii_digit()) => h.to_string(),
+                _ => p,
+            },
+        })
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::config::default_config;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn via(p: &Proxies, url: &str) -> Option<String> {
+        p.for_url(&Url::parse(url).unwrap()).map(|u| u.host_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn no_proxy_patterns() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches("example.com", "dl.example.com"));
+        assert!(!host_matches("example.com", "badexample.com"));
+        assert!(host_matches(".example.com", "dl.example.com"));
+        assert!(host_matches(".example.com", "example.com"));
+        assert!(!host_matches(".example.com", "notexample.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(host_matches("*", "anything.example"));
+        assert!(!host_matches("*x", "anything.example"));
+        assert!(host_matches("10.0.0.1", "10.0.0.1"));
+        assert!(!host_matches("10.0.0.1", "10.0.0.12"));
+    }
+
+    #[test]
+    fn no_proxy_list_entries() {
+        assert_eq!(
+            split_list(" Example.COM:8080, .corp ,10.0.0.1:3128,,[::1]:80, ::1 ,*"),
+            ["example.com", ".corp", "10.0.0.1", "::1", "::1", "*"]
+        );
+        let mut cfg = default_config();
+        cfg.no_proxy = Some("10.0.0.1:8080,[::1],.internal".into());
+        let p = Proxies::with_env(Some("http://proxy.example:3128"), &cfg, &env(&[])).unwrap();
+        assert_eq!(via(&p, "http://10.0.0.1:9000/x"), None);
+        assert_eq!(via(&p, "http://[::1]/x"), None);
+        assert_eq!(via(&p, "https://a.internal/x"), None);
+        assert_eq!(via(&p, "https://10.0.0.2/x").as_deref(), Some("proxy.example"));
+
+        cfg.no_proxy = Some("*".into());
+        let p = Proxies::with_env(Some("http://proxy.example:3128"), &cfg, &env(&[])).unwrap();
+        assert_eq!(via(&p, "https://anything.example/x"), None);
+    }
+
+    #[test]
+    fn env_vars_in_either_case() {
+        let cfg = default_config();
+        let lower = env(&[("https_proxy", "http://lower.example:1"), ("no_proxy", "skip.example")]);
+        let p = Proxies::with_env(None, &cfg, &lower).unwrap();
+        assert_eq!(via(&p, "https://a.example/").as_deref(), Some("lower.example"));
+        assert_eq!(via(&p, "http://a.example/"), None);
+        assert_eq!(via(&p, "https://skip.example/"), None);
+
+        let both = env(&[
+            ("HTTP_PROXY", "http://upper.example:1"),
+            ("http_proxy", "http://lower.example:1"),
+            ("ALL_PROXY", "socks5h://all.example:1080"),
+            ("NO_PROXY", ""),
+        ]);
+        let p = Proxies::with_env(None, &cfg, &both).unwrap();
+        assert_eq!(via(&p, "http://a.example/").as_deref(), Some("upper.example"));
+        assert_eq!(via(&p, "https://a.example/").as_deref(), Some("all.example"));
+        // a broken env proxy is ignored; a broken --proxy is an error
+        let p = Proxies::with_env(None, &cfg, &env(&[("HTTPS_PROXY", "ftp://x")])).unwrap();
+        assert_eq!(via(&p, "https://a.example/"), None);
+        assert!(Proxies::with_env(Some("ftp://x"), &cfg, &env(&[])).is_err());
+    }
+
+    #[test]
+    fn cli_and_config_win_over_env() {
+        use crate::ui::cli::{apply_overrides, Args};
+        use clap::Parser;
+
+        let vars = env(&[("HTTPS_PROXY", "http://env.example:1"), ("NO_PROXY", "a.example")]);
+        let mut cfg = default_config();
+        cfg.proxy = Some("http://config.example:2".into());
+        let p = Proxies::with_env(None, &cfg, &vars).unwrap();
+        assert_eq!(via(&p, "https://b.example/").as_deref(), Some("config.example"));
+        assert_eq!(via(&p, "https://a.example/"), None);
+
+        let args = Args::try_parse_from(["TondarDM", "--no-proxy", "b.example", "--proxy", "cli.example:3", "https://x/"])
+            .unwrap();
+        apply_overrides(&args, &mut cfg);
+        let p = Proxies::with_env(args.proxy.as_deref(), &cfg, &vars).unwrap();
+        assert_eq!(via(&p, "https://b.example/"), None);
+        // --no-proxy replaces NO_PROXY, it does not add to it
+        assert_eq!(via(&p, "https://a.example/").as_deref(), Some("cli.example"));
+
+        cfg.proxy_rules = vec![ProxyRule { host: "*.b.example".into(), proxy: "socks5h://rule.example:1080".into() }];
+        let p = Proxies::with_env(args.proxy.as_deref(), &cfg, &vars).unwrap();
+        assert_eq!(via(&p, "https://dl.b.example/").as_deref(), Some("rule.example"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Batch input (`-i urls.txt`, `-i -`): one download per line, aria2 style
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! ```text
+//! # comment
+//! https://a.example/big.iso<TAB>https://b.example/big.iso   ← same file (mirrors)
+//!   out=debian.iso
+//!   dir=/data/iso
+//!   header=Authorization: Bearer abc
+//!   checksum=sha-256=9f86d0…
+//! https://c.example/other.zip
+//! ```
+//!
+//! Option lines start with whitespace and belong to the URL line above them.
+//! Known options: out, dir, header (repeatable), referer, checksum, split.
+
+use crate::engine::prelude::*;
+use crate::util::checksum::Checksum;
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchEntry {
+    /// first URL is the link, the rest are mirrors of the same file
+    pub uris: Vec<String>,
+    /// output file name (`out=`)
+    pub out: Option<String>,
+    /// output directory (`dir=`)
+    pub dir: Option<String>,
+    /// extra request headers, "Key: Value" (`header=`, `referer=`)
+    pub headers: Vec<String>,
+    pub checksum: Option<Checksum>,
+    /// connections for this file (`split=`)
+    pub parts: Option<usize>,
+    /// line number of the URL line, for messages (0 = from the command line)
+    pub line: usize,
+}
+
+/// read: the list from a file, or from stdin for "-"
+pub async fn read(source: &str) -> Result<Vec<BatchEntry>> {
+    let text = if source == "-" {
+        // read once at startup, before any download runs: a blocking read is fine
+        use std::io::Read;
+        let mut s = String::new();
+        std::io::stdin().read_to_string(&mut s)?;
+        s
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .map_err(|e| DmError::Config(format!("{source}: {e}")))?
+    };
+    parse(&text).map_err(|e| match e {
+        DmError::Config(msg) => DmError::Config(format!("{source}: {msg}")),
+        other => other,
+    })
+}
+
+/// parse: every entry in an input list; a bad option line is an error naming its line
+pub fn parse(text: &str) -> Result<Vec<BatchEntry>> {
+    let mut out: Vec<BatchEntry> = Vec::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = raw.trim_end_matches('\r');
+        let body = line.trim();
+        if body.is_empty() || body.starts_with('#') {
+            continue;
+        }
+
+        // indented → an option of the entry above
+        if line.starts_with([' ', '\t']) {
+            let Some(entry) = out.last_mut() else {
+                return Err(DmError::Config(format!("line {line_no}: option {body:?} before any URL")));
+            };
+            let (key, value) = body
+                .split_once('=')
+                .ok_or_else(|| DmError::Config(format!("line {line_no}: expected key=value, got {body:?}")))?;
+            set_option(entry, key.trim(), value.trim())
+                .map_err(|msg| DmError::Config(format!("line {line_no}: {msg}")))?;
+            continue;
+        }
+
+        let uris: Vec<String> = body.split('\t').map(str::trim).filter(|u| !u.is_empty()).map(str::to_string).collect();
+        out.push(BatchEntry { uris, line: line_no, ..Default::default() });
+    }
+    Ok(out)
+}
+
+/// to_text: entries back in the same format (used for the saved queue)
+pub fn to_text(entries: &[BatchEntry]) -> String {
+    let mut out = String::new();
+    for e in entries {
+        out.push_str(&e.uris.join("\t"));
+        out.push('\n');
+        let opts = [("out", e.out.clone()), ("dir", e.dir.clone())]
+            .into_iter()
+            .chain(e.headers.iter().map(|h| ("header", Some(h.clone()))))
+            .chain([("checksum", e.checksum.as_ref().map(|c| c.to_string()))])
+            .chain([("split", e.parts.map(|n| n.to_string()))]);
+        for (key, value) in opts {
+            if let Some(v) = value {
+                out.push_str(&format!("  {key}={v}\n"));
+            }
+        }
+    }
+    out
+}
+
+fn set_option(entry: &mut BatchEntry, key: &str, value: &str) -> std::result::Result<(), String> {
+    match key {
+        "out" => entry.out = Some(value.to_string()),
+        "dir" => entry.dir = Some(value.to_string()),
+        "header" => {
+            if !value.contains(':') {
+                return Err(format!("header must be \"Key: Value\", got {value:?}"));
+            }
+            entry.headers.push(value.to_string());
+        }
+        "referer" => entry.headers.push(format!("Referer: {value}")),
+        // aria2 writes `sha-256=…`; "sha256:…" works as well
+        "checksum" => entry.checksum = Some(value.parse().map_err(|e| format!("checksum: {e}"))?),
+        "split" => {
+            let n: usize = value.parse().map_err(|_| format!("split: expected a number, got {value:?}"))?;
+            entry.parts = Some(n.max(1));
+        }
+        _ => return Err(format!("unknown option {key:?} (known: out, dir, header, referer, checksum, split)")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn parse_reads_options_and_mirrors() {
+        let text = format!(
+            "# list\n\nhttps://a.example/big.iso\thttps://b.example/big.iso\n  out=debian.iso\n\tdir=/data/iso\n  \
+             header=Authorization: Bearer abc\n  referer=https://a.example/\n  checksum=sha-256={SHA}\n  split=0\n\
+             https://c.example/other.zip\r\n"
+        );
+        let entries = parse(&text).unwrap();
+        assert_eq!(entries.len(), 2);
+        let e = &entries[0];
+        assert_eq!(e.uris, ["https://a.example/big.iso", "https://b.example/big.iso"]);
+        assert_eq!((e.out.as_deref(), e.dir.as_deref()), (Some("debian.iso"), Some("/data/iso")));
+        assert_eq!(e.headers, ["Authorization: Bearer abc", "Referer: https://a.example/"]);
+        assert_eq!(e.checksum.as_ref().map(|c| c.to_string()), Some(format!("sha256:{SHA}")));
+        assert_eq!((e.parts, e.line), (Some(1), 3));
+        assert_eq!((entries[1].uris.as_slice(), entries[1].line), (&["https://c.example/other.zip".to_string()][..], 10));
+    }
+
+    #[test]
+    fn to_text_round_trips() {
+        let entries = vec![
+            BatchEntry {
+                uris: vec!["https://a.example/f%20ile.zip".into(), "https://b.example/file.zip".into()],
+                out: Some("فایل (1).zip".into()),
+                dir: Some("/home/me/دانلود".into()),
+                headers: vec!["Referer: https://a.example/page?x=1".into(), "X-Key: a=b; c".into()],
+                checksum: Some(format!("sha256:{SHA}").parse().unwrap()),
+                parts: Some(8),
+                line: 0,
+            },
+            BatchEntry { uris: vec!["https://c.example/plain".into()], ..Default::default() },
+        ];
+        let text = to_text(&entries);
+        let back = parse(&text).unwrap();
+        assert_eq!(back.len(), entries.len());
+        for (a, b) in entries.iter().zip(&back) {
+            assert_eq!(a.uris, b.uris);
+            assert_eq!(a.out, b.out);
+            assert_eq!(a.dir, b.dir);
+            assert_eq!(a.headers, b.headers);
+            assert_eq!(a.checksum, b.checksum);
+            assert_eq!(a.parts, b.parts);
+        }
+        // and the text is stable
+        assert_eq!(to_text(&back), text);
+    }
+
+    #[test]
+    fn option_before_any_url_is_an_error() {
+        let err = parse("# head\n  out=x.zip\nhttps://a.example/x\n").unwrap_err().to_string();
+        assert!(err.contains("line 2") && err.contains("before any URL"), "{err}");
+    }
+
+    #[test]
+    fn bad_option_lines_name_their_line() {
+        let err = parse("https://a.example/x\n  colour=blue\n").unwrap_err().to_string();
+        assert!(err.contains("line 2") && err.contains("unknown option \"colour\""), "{err}");
+        let err = parse("https://a.example/x\n\n  out\n").unwrap_err().to_string();
+        assert!(err.contains("line 3") && err.contains("key=value"), "{err}");
+        let err = parse("https://a.example/x\n  header=no colon\n").unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{err}");
+        let err = parse("https://a.example/x\n  split=many\n").unwrap_err().to_string();
+        assert!(err.contains("split"), "{err}");
+    }
+}
@@ -2,19 +2,20 @@
 //! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
 //!
 //! - open_for_resume(): open/create file and return current size
-//! - preallocate_if_needed(): optional preallocate to total size
+//! - preallocate_if_needed(): optional preallocate to total size (real blocks on Linux)
+//! - ensure_free_space(): refuse early instead of hitting ENOSPC halfway
 //! - finalize_sync(): fsync to ensure durability
-
-//! I/O helpers (open/resume, preallocate, finalize)
+//! - commit_part(): atomically rename the .part file to its final name
+//! - commit_part_as(): the same under another name, never replacing a file
 
 use crate::engine::prelude::*;
+use crate::engine::types::PathPlan;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
-use std::io::SeekFrom;
+use tokio::io::AsyncWriteExt;
 
 pub async fn open_for_resume(path: &str) -> Result<(File, u64)> {
     let f = OpenOptions::new()
-        .create(true).write(true).read(true)
+        .create(true).write(true).read(true).truncate(false)
         .open(path).await
         .map_err(DmError::Io)?;
 
@@ -26,10 +27,33 @@ pub async fn open_for_resume(path: &str) -> Result<(File, u64)> {
 }
 
 
+/// preallocate_if_needed: reserve blocks up to `total_size`.
+/// Linux uses fallocate(2) so the space is really ours; elsewhere (or on
+/// filesystems without fallocate support) it degrades to a sparse set_len.
 pub async fn preallocate_if_needed(file: &File, total_size: Option<u64>, existing_len: u64, preallocate: bool) -> Result<()> {
     if preallocate {
         if let Some(sz) = total_size {
             if sz > existing_len {
+                #[cfg(target_os = "linux")]
+                {
+                    use std::os::fd::AsRawFd;
+                    let fd = file.as_raw_fd();
+                    let (off, len) = (existing_len as libc::off_t, (sz - existing_len) as libc::off_t);
+                    // errno is thread-local: read it on the blocking thread
+                    let res = tokio::task::spawn_blocking(move || {
+                        match unsafe { libc::fallocate(fd, 0, off, len) } {
+                            0 => Ok(()),
+                            _ => Err(std::io::Error::last_os_error()),
+                        }
+                    })
+                    .await
+                    .map_err(|e| DmError::Other(e.to_string()))?;
+                    match res {
+                        Ok(()) => return Ok(()),
+                        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+                        Err(e) => return Err(DmError::Io(e)),
+                    }
+                }
                 file.set_len(sz).await.map_err(DmError::Io)?;
             }
         }
@@ -37,8 +61,69 @@ pub async fn preallocate_if_needed(file: &File, total_size: Option<u64>, existin
     Ok(())
 }
 
+/// ensure_free_space: statvfs on `dir`; error if fewer than `needed` bytes are available
+pub fn ensure_free_space(dir: &str, needed: u64) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        let dir = if dir.is_empty() { "." } else { dir };
+        let c = CString::new(dir).map_err(|e| DmError::Other(e.to_string()))?;
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c.as_ptr(), &mut st) } != 0 {
+            return Err(DmError::Io(std::io::Error::last_os_error()));
+        }
+        #[allow(clippy::unnecessary_cast)] // field widths differ between platforms
+        let available = st.f_bavail as u64 * st.f_frsize as u64;
+        if available < needed {
+            return Err(DmError::InsufficientSpace { needed, available });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (dir, needed);
+    Ok(())
+}
+
 pub async fn finalize_sync(file: &mut File) -> Result<()> {
     file.flush().await.map_err(DmError::Io)?;
     file.sync_all().await.map_err(DmError::Io)?;
     Ok(())
 }
+
+/// commit_part: rename `temp_name` → `final_name` (atomic within `dir`),
+/// then fsync the renamed file and the directory entry.
+pub async fn commit_part(plan: &PathPlan) -> Result<()> {
+    let final_path = plan.final_path();
+    tokio::fs::rename(plan.temp_path(), &final_path).await.map_err(DmError::Io)?;
+    sync_committed(&final_path, &plan.dir).await
+}
+
+/// commit_part_as: move the .part of `plan` to `target`'s final name, which is
+/// not ours: an existing file there is never replaced (Io AlreadyExists).
+/// A hard link makes the check and the move one step; filesystems without
+/// links (FAT, some network mounts) fall back to check-then-rename.
+pub async fn commit_part_as(plan: &PathPlan, target: &PathPlan) -> Result<()> {
+    let (part, final_path) = (plan.temp_path(), target.final_path());
+    match tokio::fs::hard_link(&part, &final_path).await {
+        Ok(()) => tokio::fs::remove_file(&part).await.map_err(DmError::Io)?,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(DmError::Io(e)),
+        Err(_) => {
+            if tokio::fs::try_exists(&final_path).await.map_err(DmError::Io)? {
+                return Err(DmError::Io(std::io::ErrorKind::AlreadyExists.into()));
+            }
+            tokio::fs::rename(&part, &final_path).await.map_err(DmError::Io)?;
+        }
+    }
+    sync_committed(&final_path, &target.dir).await
+}
+
+/// sync_committed: fsync the renamed file and the directory entry
+async fn sync_committed(final_path: &str, dir: &str) -> Result<()> {
+    File::open(final_path).await.map_err(DmError::Io)?
+        .sync_all().await.map_err(DmError::Io)?;
+    #[cfg(unix)]
+    File::open(if dir.is_empty() { "." } else { dir }).await.map_err(DmError::Io)?
+        .sync_all().await.map_err(DmError::Io)?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Metalink input: parse v3 (metalinker.org) and v4 (RFC 5854, .meta4) files
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - One MetalinkFile per <file>: name, size, full-file hashes, piece hashes
+//! - Mirrors are returned best first (v4 `priority` ascending, v3 `preference` descending)
+//! - Only http/https mirrors are kept; torrent/metaurl entries are ignored, and so
+//!   is a v3 `<url type="bittorrent">` (an http link to the .torrent, not the file)
+
+use crate::engine::prelude::*;
+use crate::util::checksum::{Algo, Checksum, Pieces};
+use roxmltree::{Document, Node};
+
+#[derive(Debug, Clone)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<u64>,
+    /// full-file digests, strongest first
+    pub hashes: Vec<Checksum>,
+    pub pieces: Option<Pieces>,
+    /// mirror URLs, best first
+    pub mirrors: Vec<String>,
+}
+
+/// is_metalink: does this input name a metalink document (by extension)?
+pub fn is_metalink(input: &str) -> bool {
+    let path = input.split(['?', '#']).next().unwrap_or(input).to_ascii_lowercase();
+    path.ends_with(".meta4") || path.ends_with(".metalink")
+}
+
+/// parse: every downloadable <file> in a v3 or v4 metalink document
+pub fn parse(xml: &str) -> Result<Vec<MetalinkFile>> {
+    let doc = Document::parse(xml).map_err(|e| DmError::Other(format!("metalink: {e}")))?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "metalink" {
+        return Err(DmError::Other(format!("metalink: unexpected root <{}>", root.tag_name().name())));
+    }
+
+    let mut out = Vec::new();
+    for file in root.descendants().filter(|n| is(n, "file")) {
+        let Some(name) = file.attribute("name").map(str::to_string) else { continue };
+        let size = child(file, "size").and_then(|n| n.text()).and_then(|t| t.trim().parse().ok());
+
+        // v4: <file><hash>, v3: <file><verification><hash>; piece hashes live under <pieces>
+        let mut hashes: Vec<Checksum> = file
+            .descendants()
+            .filter(|n| is(n, "hash") && !n.parent().is_some_and(|p| is(&p, "pieces")))
+            .filter_map(|n| format!("{}:{}", n.attribute("type")?, n.text()?.trim()).parse().ok())
+            .collect();
+        hashes.sort_by_key(|c| std::cmp::Reverse(c.algo.strength()));
+
+        let pieces = file.descendants().find(|n| is(n, "pieces")).and_then(parse_pieces);
+
+        let mut mirrors: Vec<(u64, String)> = file
+            .descendants()
+            .filter(|n| is(n, "url"))
+            .filter(|n| {
+                let kind = n.attribute("type").map(str::to_ascii_lowercase);
+                kind.is_none_or(|t| matches!(t.as_str(), "http" | "https" | "ftp"))
+            })
+            .filter_map(|n| {
+                let url = n.text()?.trim();
+                if !(url.starts_with("http://") || url.starts_with("https://")) {
+                    return None;
+                }
+                let rank = match (n.attribute("priority"), n.attribute("preference")) {
+                    (Some(p), _) => p.parse().unwrap_or(999_999),
+                    // v3: 100 is best; map onto v4's "lower is better"
+                    (None, Some(p)) => 101u64.saturating_sub(p.parse().unwrap_or(0)),
+                    (None, None) => 999_999,
+                };
+                Some((rank, url.to_string()))
+            })
+            .collect();
+        mirrors.sort_by_key(|(rank, _)| *rank);
+
+        out.push(MetalinkFile {
+            name,
+            size,
+            hashes,
+            pieces,
+            mirrors: mirrors.into_iter().map(|(_, u)| u).collect(),
+        });
+    }
+    if out.is_empty() {
+        return Err(DmError::Other("metalink: no <file> entries".into()));
+    }
+    Ok(out)
+}
+
+/// parse_pieces: <pieces length="…" type="sha-1"><hash>…</hash>…</pieces>
+/// (v3 numbers its hashes with `piece="N"`; v4 relies on document order)
+fn parse_pieces(node: Node) -> Option<Pieces> {
+    let algo: Algo = node.attribute("type")?.parse().ok()?;
+    let length: u64 = node.attribute("length")?.parse().ok().filter(|&n| n > 0)?;
+    let mut hashes: Vec<(usize, String)> = node
+        .children()
+        .filter(|n| is(n, "hash"))
+        .enumerate()
+        .filter_map(|(i, n)| {
+            let idx = n.attribute("piece").and_then(|p| p.parse().ok()).unwrap_or(i);
+            let hex = n.text()?.trim().to_ascii_lowercase();
+            (hex.len() == algo.hex_len()).then_some((idx, hex))
+        })
+        .collect();
+    hashes.sort_by_key(|(i, _)| *i);
+    (!hashes.is_empty()).then(|| Pieces { algo, length, hashes: hashes.into_iter().map(|(_, h)| h).collect() })
+}
+
+fn is(n: &Node, name: &str) -> bool {
+    n.is_element() && n.tag_name().name() == name
+}
+
+fn child<'a>(n: Node<'a, 'a>, name: &str) -> Option<Node<'a, 'a>> {
+    n.children().find(|c| is(c, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1_A: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const SHA1_B: &str = "84983e441c3bd26ebaae4aa1f95129e5e54670f1";
+    const SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn v3_preference_pieces_and_torrent_links() {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink version="3.0" xmlns="http://www.metalinker.org/">
+  <files>
+    <file name="debian.iso">
+      <size>524288</size>
+      <verification>
+        <hash type="md5">900150983cd24fb0d6963f7d28e17f72</hash>
+        <hash type="sha1">{SHA1_A}</hash>
+        <pieces length="262144" type="sha1">
+          <hash piece="1">{SHA1_B}</hash>
+          <hash piece="0">{SHA1_A}</hash>
+        </pieces>
+      </verification>
+      <resources>
+        <url type="bittorrent" preference="100">http://tracker.example/debian.iso.torrent</url>
+        <url type="http" preference="10">http://slow.example/debian.iso</url>
+        <url type="http" preference="90">http://fast.example/debian.iso</url>
+        <url type="ftp" preference="95">ftp://ftp.example/debian.iso</url>
+        <url preference="50">https://plain.example/debian.iso</url>
+      </resources>
+    </file>
+  </files>
+</metalink>"#
+        );
+        let files = parse(&xml).unwrap();
+        assert_eq!(files.len(), 1);
+        let f = &files[0];
+        assert_eq!((f.name.as_str(), f.size), ("debian.iso", Some(524288)));
+        assert_eq!(
+            f.mirrors,
+            ["http://fast.example/debian.iso", "https://plain.example/debian.iso", "http://slow.example/debian.iso"]
+        );
+        // strongest digest first; the piece hashes are not full-file hashes
+        assert_eq!(f.hashes.iter().map(|c| c.algo).collect::<Vec<_>>(), [Algo::Sha1, Algo::Md5]);
+        assert_eq!(f.hashes[0].hex, SHA1_A);
+        let pieces = f.pieces.as_ref().unwrap();
+        assert_eq!((pieces.algo, pieces.length), (Algo::Sha1, 262144));
+        assert_eq!(pieces.hashes, [SHA1_A, SHA1_B]);
+    }
+
+    #[test]
+    fn v4_priority_and_pieces_in_order() {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="a.bin">
+    <size>300000</size>
+    <hash type="sha-256">{SHA256}</hash>
+    <pieces length="262144" type="sha-1">
+      <hash>{SHA1_B}</hash>
+      <hash>{SHA1_A}</hash>
+      <hash>too-short</hash>
+    </pieces>
+    <metaurl mediatype="torrent">http://t.example/a.torrent</metaurl>
+    <url location="de" priority="2">http://two.example/a.bin</url>
+    <url location="us" priority="1">https://one.example/a.bin</url>
+    <url>http://any.example/a.bin</url>
+  </file>
+  <file name="b.bin">
+    <url priority="1">ftp://only-ftp.example/b.bin</url>
+  </file>
+</metalink>"#
+        );
+        let files = parse(&xml).unwrap();
+        assert_eq!(files.len(), 2);
+        let a = &files[0];
+        assert_eq!(a.mirrors, ["https://one.example/a.bin", "http://two.example/a.bin", "http://any.example/a.bin"]);
+        assert_eq!(a.hashes.len(), 1);
+        assert_eq!(a.hashes[0].to_string(), format!("sha256:{SHA256}"));
+        let pieces = a.pieces.as_ref().unwrap();
+        assert_eq!(pieces.hashes, [SHA1_B, SHA1_A]);
+        // no usable mirror: the caller reports it, the parser keeps the file
+        assert!(files[1].mirrors.is_empty() && files[1].size.is_none());
+    }
+
+    #[test]
+    fn not_a_metalink() {
+        assert!(parse("<html><body/></html>").is_err());
+        assert!(parse("<metalink/>").is_err());
+        assert!(parse("not xml").is_err());
+        assert!(is_metalink("https://a.example/x.meta4?sig=1") && is_metalink("X.METALINK"));
+        assert!(!is_metalink("https://a.example/x.iso"));
+    }
+}
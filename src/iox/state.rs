@@ -1,31 +1,105 @@
-//! State file i/o (save/load simple resume info).
+//! State file i/o (save/load resume info, versioned).
+//! `<data file>.state` sits next to the file it describes.
 //! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - Every file carries `version`; older files are migrated on load
+//! - Saves go to `<state>.tmp`, are fsynced, then renamed over the old file,
+//!   so a crash mid-save leaves the previous state intact
+//! - The file is private (0600 on unix): saved -H headers may carry credentials
 
 use crate::engine::prelude::*;
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Current on-disk format.
+/// - 0: flat `written` counter only (no `version` field)
+/// - 1: adds `version` and per-segment progress
+/// - 2: adds the request headers (Referer, -H) a `resume` has to send again
+pub const STATE_VERSION: u32 = 2;
+
+/// SegmentState: one segment of the file; bytes `[start, pos)` are on disk, `end` is inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentState {
+    pub start: u64,
+    pub end: u64,
+    pub pos: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DlState {
+    #[serde(default)]
+    pub version: u32,
     pub url: String,
     pub filename: String,
     pub total: Option<u64>,
+    /// contiguous bytes from 0 that are on disk
     pub written: u64,
     pub etag: Option<String>,
     pub last_modified: Option<String>,
+    /// segmented downloads: progress of every segment (empty for unknown size)
+    #[serde(default)]
+    pub segments: Vec<SegmentState>,
+    /// expected digest ("algo:hex") so a resumed download is still verified
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// extra request headers, "Key: Value" (Referer, -H, `header=`): hotlink-protected
+    /// files answer 403 without them
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+impl DlState {
+    /// new: fresh state at the current version
+    pub fn new(url: &str, filename: &str, total: Option<u64>, etag: Option<String>, last_modified: Option<String>) -> Self {
+        Self {
+            version: STATE_VERSION,
+            url: url.to_string(),
+            filename: filename.to_string(),
+            total,
+            written: 0,
+            etag,
+            last_modified,
+            segments: Vec::new(),
+            checksum: None,
+            headers: Vec::new(),
+        }
+    }
+
+    /// done_bytes: bytes on disk — every segment's progress, or the contiguous prefix
+    pub fn done_bytes(&self) -> u64 {
+        match self.segments.is_empty() {
+            true => self.written,
+            false => self.segments.iter().map(|s| s.pos.saturating_sub(s.start)).sum(),
+        }
+    }
 }
 
 fn state_path(filename: &str) -> String {
     format!("{filename}.state")
 }
 
+/// data_path: the data file a `.state` file belongs to
+pub fn data_path(state_file: &str) -> Option<&str> {
+    state_file.strip_suffix(".state").filter(|s| !s.is_empty())
+}
+
 pub async fn save_state(s: &DlState) -> Result<()> {
     let path = state_path(&s.filename);
+    let tmp = format!("{path}.tmp");
     let json = serde_json::to_vec_pretty(s).map_err(|e| DmError::Other(e.to_string()))?;
-    let mut f = fs::File::create(&path).await.map_err(DmError::Io)?;
+    // -H headers (Authorization, Cookie) live in here: the file is private.
+    // A leftover .tmp would keep its old mode, so it goes first
+    let _ = fs::remove_file(&tmp).await;
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    opts.mode(0o600);
+    let mut f = opts.open(&tmp).await.map_err(DmError::Io)?;
     f.write_all(&json).await.map_err(DmError::Io)?;
     f.flush().await.map_err(DmError::Io)?;
+    f.sync_all().await.map_err(DmError::Io)?;
+    fs::rename(&tmp, &path).await.map_err(DmError::Io)?;
     Ok(())
 }
 
@@ -37,3 +111,148 @@ pub async fn remove_state(filename: &str) -> Result<()> {
         Err(e) => Err(DmError::Io(e)),
     }
 }
+
+/// load_state: read a `.state` file written by save_state (any version ≤ STATE_VERSION)
+pub async fn load_state(state_file: &str) -> Result<DlState> {
+    let bytes = fs::read(state_file).await.map_err(DmError::Io)?;
+    let s: DlState = serde_json::from_slice(&bytes)
+        .map_err(|e| DmError::Other(format!("{state_file}: {e}")))?;
+    migrate(s).map_err(|e| DmError::Other(format!("{state_file}: {e}")))
+}
+
+/// load_state_for: the state of data file `filename`, if there is one
+pub async fn load_state_for(filename: &str) -> Result<Option<DlState>> {
+    let path = state_path(filename);
+    match fs::metadata(&path).await {
+        Ok(_) => load_state(&path).await.map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(DmError::Io(e)),
+    }
+}
+
+/// migrate: bring an older state forward one version at a time
+fn migrate(mut s: DlState) -> std::result::Result<DlState, String> {
+    if s.version > STATE_VERSION {
+        return Err(format!("state version {} is newer than supported ({STATE_VERSION})", s.version));
+    }
+    if s.version == 0 {
+        // v0 only knew a single stream: one segment from 0 to total
+        if let Some(total) = s.total.filter(|&t| t > 0) {
+            s.segments = vec![SegmentState { start: 0, end: total - 1, pos: s.written.min(total) }];
+        }
+        s.version = 1;
+    }
+    if s.version == 1 {
+        // v1 did not keep headers: the download resumes without them, as before
+        s.headers.clear();
+        s.version = 2;
+    }
+    Ok(s)
+}
+
+/// find_states: every `*.state` file directly inside `dir`, sorted by name
+pub async fn find_states(dir: &str) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    let mut rd = fs::read_dir(dir).await.map_err(DmError::Io)?;
+    while let Some(ent) = rd.next_entry().await.map_err(DmError::Io)? {
+        let p = ent.path();
+        if p.extension().is_some_and(|e| e == "state") && p.is_file() {
+            out.push(p.to_string_lossy().into_owned());
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("tondar-state-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("file.zip.tondar.part").to_string_lossy().into_owned()
+    }
+
+    async fn load(name: &str, json: &str) -> Result<DlState> {
+        let sf = format!("{}.state", temp(name));
+        std::fs::write(&sf, json).unwrap();
+        let st = load_state(&sf).await;
+        std::fs::remove_dir_all(std::path::Path::new(&sf).parent().unwrap()).unwrap();
+        st
+    }
+
+    #[tokio::test]
+    async fn baseline_state_is_migrated() {
+        // the original format: no version, one flat counter
+        let st = load(
+            "v0",
+            r#"{"url":"https://a.example/f.iso","filename":"f.iso.tondar.part","total":1000,"written":400,
+                "etag":"\"abc\"","last_modified":null}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(st.version, STATE_VERSION);
+        assert_eq!(st.segments, [SegmentState { start: 0, end: 999, pos: 400 }]);
+        assert_eq!((st.written, st.done_bytes()), (400, 400));
+        assert_eq!(st.etag.as_deref(), Some("\"abc\""));
+        assert!(st.checksum.is_none() && st.headers.is_empty());
+
+        // unknown size: nothing to split; a counter past the end is clamped
+        let st = load("v0-nosize", r#"{"url":"u","filename":"f","total":null,"written":7,"etag":null,"last_modified":null}"#)
+            .await
+            .unwrap();
+        assert!(st.segments.is_empty());
+        assert_eq!(st.done_bytes(), 7);
+        let st = load("v0-over", r#"{"url":"u","filename":"f","total":10,"written":15,"etag":null,"last_modified":null}"#)
+            .await
+            .unwrap();
+        assert_eq!(st.segments, [SegmentState { start: 0, end: 9, pos: 10 }]);
+    }
+
+    #[tokio::test]
+    async fn v1_state_is_migrated() {
+        let st = load(
+            "v1",
+            r#"{"version":1,"url":"https://a.example/f.iso","filename":"f.iso.tondar.part","total":200,"written":50,
+                "etag":null,"last_modified":"Wed, 21 Oct 2015 07:28:00 GMT",
+                "segments":[{"start":0,"end":99,"pos":50},{"start":100,"end":199,"pos":180}],
+                "checksum":"sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(st.version, 2);
+        assert_eq!(st.segments.len(), 2);
+        assert_eq!(st.done_bytes(), 130);
+        assert!(st.checksum.as_deref().is_some_and(|c| c.starts_with("sha256:")));
+        assert!(st.headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn newer_versions_are_refused() {
+        let err = load("v9", r#"{"version":9,"url":"u","filename":"f","total":null,"written":0,"etag":null,"last_modified":null}"#)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("newer than supported"), "{err}");
+        assert!(load("junk", "not json").await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn state_with_headers_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let data = temp("mode");
+        // a stale world-readable .tmp from a crash must not keep its mode
+        std::fs::write(format!("{data}.state.tmp"), b"{}").unwrap();
+        std::fs::set_permissions(format!("{data}.state.tmp"), std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut st = DlState::new("https://a.example/file.zip", &data, Some(10), None, None);
+        st.headers = vec!["Authorization: Bearer sekrit".into()];
+        save_state(&st).await.unwrap();
+        let mode = std::fs::metadata(format!("{data}.state")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_state_for(&data).await.unwrap().unwrap().headers, st.headers);
+        std::fs::remove_dir_all(std::path::Path::new(&data).parent().unwrap()).unwrap();
+    }
+}
@@ -1,2 +1,9 @@
 //! Library root for tondar_dm
 pub mod net; // exposes src/net/*
+pub mod engine;
+pub mod util;
+pub mod ui { pub mod cli; }
+pub mod http { pub mod client; pub mod auth; pub mod proxy; pub mod cookies; }
+pub mod download { pub mod single; pub mod multi; }
+pub mod iox { pub mod file; pub mod state; pub mod metalink; pub mod batch; }
+pub mod queue { pub mod scheduler; }
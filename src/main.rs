@@ -1,73 +1,1108 @@
 //! TondarDM — Phase 2 integrated with probe (final URL + If-Range)
 
-mod ui { pub mod cli; }
-mod http { pub mod client; }
-mod download { pub mod single; }
-mod net { pub mod request; pub mod inspect; pub mod url; }
-mod engine {pub mod error; pub mod config; pub mod consts; pub mod prelude; pub mod  types;}
-mod util {pub mod format;}
-mod iox {pub mod file; pub mod state; }
-
-use crate::engine::prelude::{Result, DmError};   // ← مهم
-use crate::net::inspect::{self, ProbeMode};
-use crate::net::url::normalize_url;
-use crate::ui::cli;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use reqwest::Client;
+use tokio::io::AsyncWriteExt;
+use tondar_dm::engine::config::{self, Config};
+use tondar_dm::engine::prelude::{Result, DmError};   // ← مهم
+use tondar_dm::engine::types::{CancelToken, DlOpts, JobId, OnConflict, PathPlan};
+use tondar_dm::http::auth::Auth;
+use tondar_dm::http::cookies::CookieJar;
+use tondar_dm::http::proxy::{self, Proxies};
+use tondar_dm::iox::batch::{self, BatchEntry};
+use tondar_dm::iox::{metalink, state as dlstate};
+use tondar_dm::net::inspect::{self, MetaInfo, ProbeMode};
+use tondar_dm::net::page;
+use tondar_dm::net::retry::{self, Backoff, RetryPolicy};
+use tondar_dm::net::url::normalize_url;
+use tondar_dm::queue::scheduler::{JobStatus, Priority, Scheduler};
+use tondar_dm::ui::cli;
+use tondar_dm::util::checksum::{Checksum, Pieces};
+use tondar_dm::util::filename;
+use tondar_dm::util::format::format_size;
+use tondar_dm::util::rate::{parse_rate, RateLimiter};
+
+/// Task: one queued download — probe result, destination and what to verify
+struct Task {
+    /// the link as given (normalized); probed again if the final URL goes stale
+    link: String,
+    meta: MetaInfo,
+    plan: PathPlan,
+    checksum: Option<Checksum>,
+    /// other URLs for the same file (metalink), tried in order if the download fails
+    mirrors: Vec<String>,
+    pieces: Option<Arc<Pieces>>,
+    /// extra sources downloaded from in parallel (`--mirror`, already checked)
+    sources: Vec<MetaInfo>,
+    /// client for this download (a batch entry may bring its own headers)
+    client: Client,
+    /// connections for this file (`split=`), else config `default_parts`
+    parts: Option<usize>,
+    /// index of the link (command line / `-i` / queue entry) this task came from
+    input: usize,
+    /// `--on-conflict overwrite`: start from zero even if a .part is there
+    overwrite: bool,
+    /// extra request headers (Referer, -H, `header=`), saved so `resume` sends them again
+    headers: Vec<String>,
+    /// where the file ended up, when the content called for another extension
+    saved_as: OnceLock<String>,
+}
+
+type Jobs = HashMap<JobId, Task>;
+
+/// Net: what every network command shares — proxies, one cookie jar, credentials
+struct Net {
+    proxies: Proxies,
+    jar: Arc<CookieJar>,
+    /// profile cookie jar file (None with `keep_cookies = false`)
+    profile_jar: Option<String>,
+    client: Client,
+    auth: Arc<Auth>,
+}
+
+impl Net {
+    fn new(args: &cli::Args, cfg: &Config, cfg_path: &str) -> Result<Self> {
+        let proxies = Proxies::new(args.proxy.as_deref(), cfg)?;
+        // cookies: profile jar, then --load-cookies (newer entries replace older ones;
+        // imported ones are used for this run but never saved to the profile)
+        let jar = Arc::new(CookieJar::new(args.cookie.as_deref()));
+        let profile_jar = cfg.keep_cookies.then(|| config::profile_file(cfg_path, "cookies.txt"));
+        if let Some(path) = &profile_jar {
+            jar.load_profile(path)?;
+        }
+        if let Some(path) = &args.load_cookies {
+            let n = jar.import(path)?;
+            println!("Loaded {n} cookie(s) from {path}");
+        }
+        let client = tondar_dm::http::client::build_client(args, &proxies, jar.clone(), &args.headers)?;
+        let auth = Arc::new(Auth::new(args.user.as_deref(), args.password.as_deref(), args.bearer.as_deref()));
+        Ok(Net { proxies, jar, profile_jar, client, auth })
+    }
+
+    /// client_for: entries with their own headers get their own client (same proxies and jar)
+    fn client_for(&self, args: &cli::Args, entry: &BatchEntry) -> Result<Client> {
+        if entry.headers.is_empty() {
+            return Ok(self.client.clone());
+        }
+        let headers = [args.headers.clone(), entry.headers.clone()].concat();
+        tondar_dm::http::client::build_client(args, &self.proxies, self.jar.clone(), &headers)
+    }
+
+    /// inputs: every entry paired with the client it is fetched with
+    fn inputs(&self, args: &cli::Args, entries: Vec<BatchEntry>) -> Result<Vec<(BatchEntry, Client)>> {
+        entries
+            .into_iter()
+            .map(|mut e| {
+                let client = self.client_for(args, &e)?;
+                e.headers = request_headers(args, &e.headers);
+                Ok((e, client))
+            })
+            .collect()
+    }
+
+    /// allow: a host the user named gets the explicit credentials and the `--cookie` line
+    fn allow(&self, url: &str) {
+        self.auth.allow(url);
+        self.jar.allow(url);
+    }
+
+    /// save_cookies: write the jar to the profile and to --save-cookies; a failure only warns
+    fn save_cookies(&self, save_to: Option<&str>) {
+        let profile = self.profile_jar.as_deref().map(|p| (p, self.jar.save_profile(p)));
+        let explicit = save_to.map(|p| (p, self.jar.save(p)));
+        for (path, res) in profile.into_iter().chain(explicit) {
+            if let Err(e) = res {
+                eprintln!("Warning: could not save cookies to {path}: {e}");
+            }
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // parse CLI args
     let args = cli::parse_args();
-    let client = crate::http::client::build_client(&args)?;
 
-    // normalize URL (unwrap href.li and similar wrappers)
-    let normalized = normalize_url(&args.url);
+    // config: file ← TONDAR_* env ← CLI flags
+    let cfg_path = config::config_path(args.config.as_deref());
+    let mut cfg = config::load_config(&cfg_path)?;
+    if let Some(cli::Command::Config { action }) = &args.command {
+        return run_config(action, &cfg_path, cfg);
+    }
+    config::apply_env(&mut cfg)?;
+    cli::apply_overrides(&args, &mut cfg);
+    let queue_file = config::profile_file(&cfg_path, "queue.txt");
 
-    // probe file meta
-    let meta = inspect::probe_url(&client, &normalized, ProbeMode::Auto)
-        .await
-        .map_err(|e| DmError::Other(format!("probe failed: {e}")))?;
+    // commands that stay offline
+    match &args.command {
+        Some(cli::Command::List { path }) => return run_list(path.as_deref().unwrap_or(&cfg.output_dir)).await,
+        Some(cli::Command::Queue { action: cli::QueueAction::Add { urls } }) => {
+            if let Some(rate) = &args.limit_rate {
+                return Err(DmError::Other(format!(
+                    "--limit-rate {rate} is not saved with queued links; give it to `queue run`"
+                )));
+            }
+            let mut entries = read_entries(&args, urls).await?;
+            for e in entries.iter_mut() {
+                // what the run would have used had the links been fetched now
+                if e.dir.is_none() {
+                    e.dir = args.output_dir.clone();
+                }
+                if e.parts.is_none() {
+                    e.parts = args.parts;
+                }
+                let has_referer = e.headers.iter().any(|h| h.to_ascii_lowercase().starts_with("referer:"));
+                if let (Some(r), false) = (&args.referer, has_referer) {
+                    e.headers.push(format!("Referer: {r}"));
+                }
+                e.headers.extend(args.headers.iter().filter(|h| !e.headers.contains(h)).cloned().collect::<Vec<_>>());
+            }
+            let mut queued = read_queue(&queue_file).await?;
+            queued.extend(entries);
+            write_queue(&queue_file, &queued).await?;
+            println!("{} link(s) in the queue ({queue_file})", queued.len());
+            return Ok(());
+        }
+        Some(cli::Command::Queue { action: cli::QueueAction::Show }) => {
+            let queued = read_queue(&queue_file).await?;
+            match queued.is_empty() {
+                true => println!("The queue is empty."),
+                false => print!("{}", batch::to_text(&queued)),
+            }
+            return Ok(());
+        }
+        Some(cli::Command::Queue { action: cli::QueueAction::Clear }) => {
+            write_queue(&queue_file, &[]).await?;
+            println!("Queue cleared.");
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    cli::print_meta(&meta.final_url, &meta.filename, meta.size, meta.accept_ranges);
+    let net = Net::new(&args, &cfg, &cfg_path)?;
+    let sched = Scheduler::new(&cfg);
+
+    // probe every link first so the user sees what will be fetched;
+    // in a batch a link that fails is reported and the rest still run
+    let mut failures: Vec<(String, String)> = Vec::new();
+    match &args.command {
+        Some(cli::Command::Probe { url, force_range }) => run_probe(&net, &args, &cfg, url, *force_range).await,
+        Some(cli::Command::Resume { path }) => {
+            let path = path.clone().unwrap_or_else(|| cfg.output_dir.clone());
+            let jobs = queue_resume(&net, &args, &sched, &path, &cfg, &mut failures).await?;
+            let statuses = run_jobs(&net, &args, &cfg, &sched, jobs, &mut failures).await?;
+            finish(&statuses.unwrap_or_default(), &failures)
+        }
+        Some(cli::Command::Queue { action: cli::QueueAction::Run }) => {
+            let queued = read_queue(&queue_file).await?;
+            let (jobs, skipped) =
+                queue_urls(&net, &sched, net.inputs(&args, queued.clone())?, &cfg, &mut failures).await?;
+            let Some(statuses) = run_jobs(&net, &args, &cfg, &sched, jobs, &mut failures).await? else {
+                return Ok(());
+            };
+            // a link stays queued until everything it stands for is downloaded
+            let mut finished: Vec<String> = queued
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| {
+                    // skipped: the file is already there
+                    if skipped.contains(i) {
+                        return true;
+                    }
+                    let mut mine = statuses.iter().filter(|(input, _)| input == i).peekable();
+                    mine.peek().is_some() && mine.all(|(_, st)| *st == JobStatus::Done)
+                })
+                .map(|(_, e)| queue_key(e))
+                .collect();
+            // `queue add` may have run meanwhile: re-read and drop only what finished here
+            let left: Vec<BatchEntry> = read_queue(&queue_file)
+                .await?
+                .into_iter()
+                .filter(|e| match finished.iter().position(|k| *k == queue_key(e)) {
+                    Some(i) => {
+                        finished.swap_remove(i);
+                        false
+                    }
+                    None => true,
+                })
+                .collect();
+            write_queue(&queue_file, &left).await?;
+            if !left.is_empty() {
+                println!("{} link(s) left in the queue", left.len());
+            }
+            finish(&statuses, &failures)
+        }
+        Some(cli::Command::Get { urls }) => run_get(&net, &args, &cfg, &sched, urls, &mut failures).await,
+        _ => run_get(&net, &args, &cfg, &sched, &args.urls, &mut failures).await,
+    }
+}
 
-    if !cli::confirm("Start download now?") {
+/// run_get: download links from the command line and `-i`
+async fn run_get(
+    net: &Net,
+    args: &cli::Args,
+    cfg: &Config,
+    sched: &Scheduler,
+    urls: &[String],
+    failures: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let entries = read_entries(args, urls).await?;
+    if entries.is_empty() {
+        return Err(DmError::Other("nothing to download: give links or -i FILE (see --help)".into()));
+    }
+    let (jobs, _) = queue_urls(net, sched, net.inputs(args, entries)?, cfg, failures).await?;
+    let statuses = run_jobs(net, args, cfg, sched, jobs, failures).await?;
+    finish(&statuses.unwrap_or_default(), failures)
+}
+
+/// read_entries: links from the command line first, then `-i` (file or stdin);
+/// --checksum and --mirror belong to a lone link
+async fn read_entries(args: &cli::Args, urls: &[String]) -> Result<Vec<BatchEntry>> {
+    for u in urls.iter().chain(&args.mirrors) {
+        check_link(u)?;
+    }
+    let mut entries: Vec<BatchEntry> =
+        urls.iter().map(|u| BatchEntry { uris: vec![u.clone()], ..Default::default() }).collect();
+    if let Some(src) = &args.input {
+        entries.extend(batch::read(src).await?);
+    }
+    let checksum = match &args.checksum {
+        Some(s) if entries.len() > 1 => {
+            return Err(DmError::Other(format!("--checksum {s:?} applies to a single link, got {}", entries.len())))
+        }
+        Some(s) => Some(s.parse::<Checksum>().map_err(|e| DmError::Other(format!("invalid --checksum: {e}")))?),
+        None => None,
+    };
+    if !args.mirrors.is_empty() && entries.len() > 1 {
+        return Err(DmError::Other(format!("--mirror applies to a single link, got {}", entries.len())));
+    }
+    if let [only] = entries.as_mut_slice() {
+        only.uris.extend(args.mirrors.iter().cloned());
+        only.checksum = only.checksum.take().or(checksum);
+    }
+    Ok(entries)
+}
+
+/// check_link: an http(s) URL or a .meta4/.metalink file; anything else (a mistyped
+/// command, a bare host) is refused here rather than failing deep inside reqwest
+fn check_link(input: &str) -> Result<()> {
+    if metalink::is_metalink(input) {
+        return Ok(());
+    }
+    match url::Url::parse(input) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") && u.has_host() => Ok(()),
+        _ => Err(DmError::Other(format!(
+            "{input:?} is not an http:// or https:// link (commands: get, probe, resume, list, queue, config)"
+        ))),
+    }
+}
+
+/// run_jobs: confirm, then download every queued task. Returns how each one ended,
+/// keyed by the input it came from, or None if the user said no.
+async fn run_jobs(
+    net: &Net,
+    args: &cli::Args,
+    cfg: &Config,
+    sched: &Scheduler,
+    jobs: Jobs,
+    failures: &mut Vec<(String, String)>,
+) -> Result<Option<Vec<(usize, JobStatus)>>> {
+    // whatever the probes were handed (login redirects, CDN tokens…) survives an abort
+    net.save_cookies(args.save_cookies.as_deref());
+    if jobs.is_empty() {
+        println!("Nothing to download.");
+        return Ok(Some(Vec::new()));
+    }
+
+    if !cli::confirm("Start download now?", args.yes) {
         println!("Aborted by user.");
+        return Ok(None);
+    }
+
+    // Ctrl+C → pause every running job (each one saves its .state)
+    {
+        let sched = sched.clone();
+        tokio::spawn(async move {
+            let _ = tokio::signal::ctrl_c().await;
+            sched.pause_all();
+        });
+    }
+
+    let global_rate = parse_rate_opt("rate_limit_global", cfg.rate_limit_global.as_deref())?;
+    let job_rate = parse_rate_opt("--limit-rate", args.limit_rate.as_deref())?;
+    let global_limiter = global_rate.map(RateLimiter::new);
+    let base = DlOpts {
+        parts: cfg.default_parts,
+        if_range: args.if_range,
+        resume: cfg.resume,
+        preallocate: cfg.preallocate,
+        auth: net.auth.clone(),
+        retry: retry_policy(cfg),
+        ..Default::default()
+    };
+    let jobs = Arc::new(jobs);
+
+    let results = sched
+        .run(|job, cancel| {
+            let jobs = jobs.clone();
+            // the global bucket is shared; the per-job one is fresh for every job
+            let limiters = global_limiter.iter().cloned()
+                .chain(job_rate.map(RateLimiter::new))
+                .collect();
+            let task = &jobs[&job.id];
+            let client = task.client.clone();
+            let (checksum, pieces, sources) = (task.checksum.clone(), task.pieces.clone(), task.sources.clone());
+            let parts = task.parts.unwrap_or(base.parts);
+            let resume = base.resume && !task.overwrite;
+            let headers = task.headers.clone();
+            let opts = DlOpts { cancel, limiters, checksum, pieces, sources, parts, resume, headers, ..base.clone() };
+            Box::pin(async move {
+                let Task { link, meta, plan, mirrors, saved_as, .. } = &jobs[&job.id];
+
+                // check if file already partially exists
+                let existing: u64 = match tokio::fs::metadata(plan.temp_path()).await {
+                    Ok(m) => m.len(),
+                    Err(_) => 0,
+                };
+                if existing > 0 && opts.resume && meta.accept_ranges {
+                    println!("Resuming download: {}", plan.final_name);
+                } else if existing > 0 && opts.resume {
+                    println!("Starting single download (server does not support resume): {}", plan.final_name);
+                } else if opts.parts > 1 && meta.accept_ranges && meta.size.is_some() && !opts.sources.is_empty() {
+                    println!(
+                        "Starting segmented download ({} parts, {} sources): {}",
+                        opts.parts,
+                        opts.sources.len() + 1,
+                        plan.final_name
+                    );
+                } else if opts.parts > 1 && meta.accept_ranges && meta.size.is_some() {
+                    println!("Starting segmented download ({} parts): {}", opts.parts, plan.final_name);
+                } else {
+                    println!("Starting single download: {}", plan.final_name);
+                }
+
+                // run the download (falls back to single when ranges are unsupported);
+                // on failure move on to the next mirror, which resumes the same .part
+                let mut res = tondar_dm::download::multi::download_multi(&client, meta, plan, &opts).await;
+                // the final URL stopped working (403/404, 200 to a range): one fresh probe
+                if matches!(res, Err(DmError::Stale(_))) {
+                    res = match reprobe(&client, &opts, link, meta, plan).await {
+                        Ok(m) => tondar_dm::download::multi::download_multi(&client, &m, plan, &opts).await,
+                        Err(e) => Err(e),
+                    };
+                }
+                for url in mirrors {
+                    match &res {
+                        Err(e) if fails_over(e) => eprintln!("{}: {e}; trying mirror {url}", plan.final_name),
+                        _ => break,
+                    }
+                    res = match probe_mirror(&client, &opts.auth, url, meta.size, &plan.final_name, opts.retry).await {
+                        Ok(m) => tondar_dm::download::multi::download_multi(&client, &m, plan, &opts).await,
+                        Err(e) => Err(e),
+                    };
+                }
+                if matches!(res, Err(DmError::WrongContent { .. })) {
+                    drop_unstarted(plan).await;
+                }
+                // the content may have called for another extension
+                let done = res?;
+                if done.final_name != plan.final_name {
+                    let _ = saved_as.set(done.final_path());
+                }
+                Ok(())
+            })
+        })
+        .await;
+
+    net.save_cookies(args.save_cookies.as_deref());
+
+    let mut statuses = Vec::new();
+    for (job, status) in results {
+        let task = &jobs[&job.id];
+        let name = task.saved_as.get().cloned().unwrap_or_else(|| task.plan.final_path());
+        match &status {
+            JobStatus::Done => println!("✅ Download completed: {name}"),
+            JobStatus::Paused => println!("⏸️ Paused by user (state saved): {name}"),
+            JobStatus::Cancelled => println!("Cancelled: {name}"),
+            JobStatus::Failed(msg) => {
+                eprintln!("❌ Download failed: {name}: {msg}");
+                failures.push((name, msg.clone()));
+            }
+            // the scheduler only returns once nothing runs; anything left here never ended
+            JobStatus::Queued | JobStatus::Running => {
+                let msg = "did not finish".to_string();
+                eprintln!("❌ Download failed: {name}: {msg}");
+                failures.push((name, msg));
+            }
+        }
+        statuses.push((task.input, status));
+    }
+    Ok(Some(statuses))
+}
+
+/// run_probe: print what `url` would download (and which proxy it goes through)
+async fn run_probe(net: &Net, args: &cli::Args, cfg: &Config, url: &str, force_range: bool) -> Result<()> {
+    let url = normalize_url(url);
+    net.allow(&url);
+    let mode = if force_range { ProbeMode::GetRange0 } else { ProbeMode::Auto };
+    let mut meta = probe(&net.client, &net.auth, &url, mode, retry_policy(cfg)).await?;
+    if cfg.find_checksum {
+        find_checksum(&net.client, &net.auth, &mut meta).await;
+    }
+    print_proxy(&net.proxies, &url, &meta.final_url);
+    inspect::print_table(&meta);
+    if let Err(e) = page::check_probe(&net.client, &net.auth, &meta, None).await {
+        println!("\nWarning  : {e}");
+    }
+    net.save_cookies(args.save_cookies.as_deref());
+    Ok(())
+}
+
+/// print_proxy: which proxy the first request (and the final URL, after redirects) went through
+fn print_proxy(proxies: &Proxies, url: &str, final_url: &str) {
+    let via = |u: &str| match reqwest::Url::parse(u).ok().as_ref().and_then(|u| proxies.for_url(u)) {
+        Some(p) => proxy::redact(p),
+        None => "(direct)".to_string(),
+    };
+    let (first, last) = (via(url), via(final_url));
+    if first == last {
+        println!("Proxy    : {first}");
+    } else {
+        println!("Proxy    : {first} → {last} (after redirect)");
+    }
+}
+
+/// run_list: unfinished downloads (their .state files) in `path`
+async fn run_list(path: &str) -> Result<()> {
+    let files = match tokio::fs::metadata(path).await {
+        Ok(m) if m.is_dir() => dlstate::find_states(path).await?,
+        Ok(_) => vec![path.to_string()],
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if files.is_empty() {
+        println!("No unfinished downloads in {path}.");
         return Ok(());
     }
+    for sf in files {
+        let st = match dlstate::load_state(&sf).await {
+            Ok(st) => st,
+            Err(e) => {
+                eprintln!("{sf}: {e}");
+                continue;
+            }
+        };
+        let name = dlstate::data_path(&sf).and_then(PathPlan::from_temp_path).map(|p| p.final_path());
+        let done = st.done_bytes();
+        let progress = match st.total {
+            Some(total) if total > 0 => format!(
+                "{:>5.1}%  {} / {}",
+                done as f64 * 100.0 / total as f64,
+                format_size(done),
+                format_size(total)
+            ),
+            _ => format!("{} so far", format_size(done)),
+        };
+        println!("{}", name.unwrap_or(sf));
+        println!("    {progress}  {}", st.url);
+    }
+    Ok(())
+}
+
+/// read_queue: the saved queue (a missing file is an empty queue)
+async fn read_queue(path: &str) -> Result<Vec<BatchEntry>> {
+    match tokio::fs::metadata(path).await {
+        Ok(_) => batch::read(path).await,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// write_queue: replace the saved queue in one step (temp file + rename), so a
+/// reader never sees half of it
+async fn write_queue(path: &str, entries: &[BatchEntry]) -> Result<()> {
+    if let Some(dir) = std::path::Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = format!("{path}.{}.tmp", std::process::id());
+    // saved `header=Authorization: …` lines are credentials: private, like the cookie jar
+    let mut opts = tokio::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    opts.mode(0o600);
+    let written = match opts.open(&tmp).await {
+        Ok(mut f) => f.write_all(batch::to_text(entries).as_bytes()).await.and(f.flush().await),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+    if let Err(e) = tokio::fs::rename(&tmp, path).await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// queue_key: an entry as it is saved; equal keys are the same queued link
+fn queue_key(entry: &BatchEntry) -> String {
+    batch::to_text(std::slice::from_ref(entry))
+}
+
+/// request_headers: what a download sends besides the defaults — --referer, -H,
+/// then the entry's own `header=`/`referer=` lines (later ones win)
+fn request_headers(args: &cli::Args, entry_headers: &[String]) -> Vec<String> {
+    let referer = args.referer.iter().map(|r| format!("Referer: {r}"));
+    referer.chain(args.headers.iter().cloned()).chain(entry_headers.iter().cloned()).collect()
+}
+
+/// finish: a summary when more than one link was given; the run fails if any link did
+fn finish(statuses: &[(usize, JobStatus)], failures: &[(String, String)]) -> Result<()> {
+    let count = |want: &JobStatus| statuses.iter().filter(|(_, st)| st == want).count();
+    let (completed, paused) = (count(&JobStatus::Done), count(&JobStatus::Paused));
+    if completed + paused + failures.len() > 1 {
+        println!();
+        println!("Summary: {completed} completed, {} failed, {paused} paused", failures.len());
+        for (what, msg) in failures {
+            println!("  ❌ {what}: {msg}");
+        }
+    }
+    match failures {
+        [] => Ok(()),
+        [(_, msg)] => Err(DmError::Other(msg.clone())),
+        _ => Err(DmError::Other(format!("{} downloads failed", failures.len()))),
+    }
+}
+
+/// queue_urls: probe each link and queue it into its directory (`dir=`, else `output_dir`).
+/// With a single link a failure is the error; in a batch it goes to `failures`.
+/// Also returns the inputs skipped by `on_conflict = skip`.
+async fn queue_urls(
+    net: &Net,
+    sched: &Scheduler,
+    inputs: Vec<(BatchEntry, Client)>,
+    cfg: &Config,
+    failures: &mut Vec<(String, String)>,
+) -> Result<(Jobs, Vec<usize>)> {
+    let single = inputs.len() == 1;
+    let mut jobs = Jobs::new();
+    let mut skipped = Vec::new();
+    for (input, (entry, client)) in inputs.into_iter().enumerate() {
+        let res = match entry.uris.iter().try_for_each(|u| check_link(u)) {
+            Err(e) => Err(e),
+            Ok(()) if metalink::is_metalink(&entry.uris[0]) => {
+                queue_metalink(&client, &net.auth, sched, (input, &entry), cfg, &mut jobs).await
+            }
+            Ok(()) => queue_link(&client, net, sched, (input, &entry), cfg, &mut jobs).await,
+        };
+        match res {
+            Ok(true) => {}
+            Ok(false) => skipped.push(input),
+            Err(e) if single => return Err(e),
+            Err(e) => {
+                let what = match entry.line {
+                    0 => entry.uris[0].clone(),
+                    n => format!("{} (line {n})", entry.uris[0]),
+                };
+                eprintln!("❌ {what}: {e}");
+                failures.push((what, e.to_string()));
+            }
+        }
+    }
+    Ok((jobs, skipped))
+}
+
+/// queue_link: probe one plain link (plus its mirrors) and queue it;
+/// `false` when it was skipped because the file exists
+async fn queue_link(
+    client: &Client,
+    net: &Net,
+    sched: &Scheduler,
+    (input, entry): (usize, &BatchEntry),
+    cfg: &Config,
+    jobs: &mut Jobs,
+) -> Result<bool> {
+    // normalize URL (unwrap href.li and similar wrappers)
+    let normalized = normalize_url(&entry.uris[0]);
+    let auth = &net.auth;
+    net.allow(&normalized);
+
+    // probe file meta
+    let policy = retry_policy(cfg);
+    let mut meta = probe(client, auth, &normalized, ProbeMode::Auto, policy).await?;
+    if let Some(out) = &entry.out {
+        meta.filename = filename::sanitize(out);
+        meta.ext_guessed = false;
+    }
+    page::check_probe(client, auth, &meta, None).await?;
+    if cfg.find_checksum && entry.checksum.is_none() {
+        find_checksum(client, auth, &mut meta).await;
+    }
+
+    print_meta(&meta, entry.checksum.as_ref());
+
+    // every mirror must serve the very same file, with ranges
+    let mut sources = Vec::new();
+    for m in &entry.uris[1..] {
+        let m = normalize_url(m);
+        net.allow(&m);
+        match probe_source(client, auth, &m, &meta, policy).await {
+            Ok(src) => sources.push(src),
+            Err(e) => eprintln!("Rejected mirror {m}: {e}"),
+        }
+    }
 
-    // check if file already partially exists
-    let existing: u64 = match tokio::fs::metadata(&meta.filename).await {
-        Ok(m) => m.len(),
-        Err(_) => 0,
+    let dir = entry.dir.as_deref().unwrap_or(&cfg.output_dir);
+    tokio::fs::create_dir_all(dir).await?;
+    let plan = PathPlan::new(dir, &meta.filename);
+    let Some((plan, overwrite)) = place(plan, &meta, &normalized, cfg.on_conflict, jobs).await? else {
+        return Ok(false);
     };
+    let id = sched.add(&meta.final_url, Priority::Normal);
+    jobs.insert(id, Task {
+        link: normalized,
+        meta,
+        plan,
+        checksum: entry.checksum.clone(),
+        mirrors: Vec::new(),
+        pieces: None,
+        sources,
+        client: client.clone(),
+        parts: entry.parts,
+        input,
+        overwrite,
+        headers: entry.headers.clone(),
+        saved_as: OnceLock::new(),
+    });
+    Ok(true)
+}
 
-    if existing > 0 && meta.accept_ranges {
-        println!("Resuming single download...");
-    } else if existing > 0 && !meta.accept_ranges {
-        println!("Starting single download (server does not support resume)...");
+/// queue_metalink: read a metalink (local path or URL) and queue each file in it
+/// from its best reachable mirror; the remaining mirrors are kept for failover.
+/// `false` when every file in it was skipped.
+async fn queue_metalink(
+    client: &Client,
+    auth: &Auth,
+    sched: &Scheduler,
+    (input, entry): (usize, &BatchEntry),
+    cfg: &Config,
+    jobs: &mut Jobs,
+) -> Result<bool> {
+    let source = entry.uris[0].as_str();
+    let xml = if source.starts_with("http://") || source.starts_with("https://") {
+        let resp = auth.send(client.get(source)).await.map_err(|e| DmError::Network(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(DmError::HttpStatus(format!("{source}: {}", resp.status())));
+        }
+        resp.text().await.map_err(|e| DmError::Network(e.to_string()))?
     } else {
-        println!("Starting single download...");
+        tokio::fs::read_to_string(source).await?
+    };
+
+    let dir = entry.dir.as_deref().unwrap_or(&cfg.output_dir);
+    tokio::fs::create_dir_all(dir).await?;
+    let mut queued = false;
+    for file in metalink::parse(&xml)? {
+        // metalink names may carry directories; only a sanitized last component is used
+        let name = filename::sanitize(&file.name);
+        let mut mirrors = file.mirrors.into_iter();
+        let mut meta = None;
+        for url in mirrors.by_ref() {
+            match probe_mirror(client, auth, &url, file.size, &name, retry_policy(cfg)).await {
+                Ok(m) => {
+                    meta = Some((url, m));
+                    break;
+                }
+                Err(e) => eprintln!("Skipping mirror {url}: {e}"),
+            }
+        }
+        let Some((link, mut meta)) = meta else {
+            return Err(DmError::Other(format!("{source}: no usable mirror for {name}")));
+        };
+        meta.size = meta.size.or(file.size);
+
+        let checksum = entry.checksum.clone().or_else(|| file.hashes.first().cloned());
+        print_meta(&meta, checksum.as_ref());
+        let plan = PathPlan::new(dir, &name);
+        let Some((plan, overwrite)) = place(plan, &meta, &link, cfg.on_conflict, jobs).await? else {
+            continue;
+        };
+        let id = sched.add(&meta.final_url, Priority::Normal);
+        jobs.insert(id, Task {
+            link,
+            meta,
+            plan,
+            checksum,
+            mirrors: mirrors.collect(),
+            pieces: file.pieces.map(Arc::new),
+            sources: Vec::new(),
+            client: client.clone(),
+            parts: entry.parts,
+            input,
+            overwrite,
+            headers: entry.headers.clone(),
+            saved_as: OnceLock::new(),
+        });
+        queued = true;
     }
+    Ok(queued)
+}
 
-    // run the single download
-    match crate::download::single::download_single(
-        &client,
-        &meta.final_url,
-        &meta.filename,
-        meta.size,
-        meta.accept_ranges,
-        if args.if_range { meta.etag.as_deref() } else { None },
-        if args.if_range { meta.last_modified.as_deref() } else { None },
-    ).await {
-        Ok(()) => {
-            println!("✅ Download completed: {}", meta.filename);
+/// probe_mirror: probe one source of a known file; a size that disagrees rejects it
+async fn probe_mirror(
+    client: &Client,
+    auth: &Auth,
+    url: &str,
+    size: Option<u64>,
+    name: &str,
+    policy: RetryPolicy,
+) -> Result<MetaInfo> {
+    let mut meta = probe(client, auth, url, ProbeMode::Auto, policy).await?;
+    if let (Some(want), Some(got)) = (size, meta.size) {
+        if want != got {
+            return Err(DmError::Other(format!("{url}: size {got} does not match expected {want}")));
         }
-        Err(DmError::Other(msg)) if msg == "cancelled" => {
-            println!("⏸️ Paused by user (state saved).");
+    }
+    meta.filename = name.to_string();
+    meta.ext_guessed = false;
+    page::check_probe(client, auth, &meta, size).await?;
+    Ok(meta)
+}
+
+/// probe_source: probe a `--mirror` and check it serves the same file as `primary`:
+/// same size, same ETag (when both send one) and byte ranges
+async fn probe_source(
+    client: &Client,
+    auth: &Auth,
+    url: &str,
+    primary: &MetaInfo,
+    policy: RetryPolicy,
+) -> Result<MetaInfo> {
+    let mut meta = probe(client, auth, url, ProbeMode::Auto, policy).await?;
+    meta.filename = primary.filename.clone();
+    meta.ext_guessed = primary.ext_guessed;
+    page::check_probe(client, auth, &meta, primary.size).await?;
+    if !meta.accept_ranges {
+        return Err(DmError::Other("no byte-range support".into()));
+    }
+    match (primary.size, meta.size) {
+        (Some(a), Some(b)) if a == b => {}
+        (a, b) => return Err(DmError::Other(format!("size {b:?} does not match {a:?}"))),
+    }
+    let strip = |t: &String| t.trim_start_matches("W/").to_string();
+    if let (Some(a), Some(b)) = (primary.etag.as_ref().map(strip), meta.etag.as_ref().map(strip)) {
+        if a != b {
+            return Err(DmError::Other(format!("ETag {b} does not match {a}")));
         }
-        Err(e) => {
-            return Err(e);
+    }
+    Ok(meta)
+}
+
+/// fails_over: errors another mirror might not have (network, HTTP, short reads,
+/// pieces that stay corrupt; the next mirror re-fetches only those)
+fn fails_over(e: &DmError) -> bool {
+    !matches!(
+        e,
+        DmError::ChecksumMismatch { .. } | DmError::InsufficientSpace { .. } | DmError::Io(_) | DmError::Config(_)
+    ) && !matches!(e, DmError::Other(msg) if msg == "cancelled")
+}
+
+/// queue_resume: reload `.state` files (one file, or all in a directory), re-probe
+/// the saved URL and queue each download to continue where it stopped.
+/// For a directory, a broken or stale `.state` goes to `failures` and the rest still run.
+async fn queue_resume(
+    net: &Net,
+    args: &cli::Args,
+    sched: &Scheduler,
+    path: &str,
+    cfg: &Config,
+    failures: &mut Vec<(String, String)>,
+) -> Result<Jobs> {
+    let (files, single) = match tokio::fs::metadata(path).await {
+        Ok(m) if m.is_dir() => (dlstate::find_states(path).await?, false),
+        Ok(_) => (vec![path.to_string()], true),
+        // the default output directory is only created by the first download
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && path == cfg.output_dir => (Vec::new(), false),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut jobs = Jobs::new();
+    for (input, sf) in files.into_iter().enumerate() {
+        match resume_state(net, args, sched, (input, &sf), cfg, &mut jobs).await {
+            Ok(()) => {}
+            Err(e) if single => return Err(e),
+            Err(e) => {
+                eprintln!("❌ {sf}: {e}");
+                failures.push((sf, e.to_string()));
+            }
         }
     }
+    Ok(jobs)
+}
+
+/// resume_state: queue the download one `.state` file describes, sending the
+/// headers (Referer, -H) the original run sent
+async fn resume_state(
+    net: &Net,
+    args: &cli::Args,
+    sched: &Scheduler,
+    (input, sf): (usize, &str),
+    cfg: &Config,
+    jobs: &mut Jobs,
+) -> Result<()> {
+    let st = dlstate::load_state(sf).await?;
+    let auth = &net.auth;
+    net.allow(&st.url);
+    let client = &net.client_for(args, &BatchEntry { headers: st.headers.clone(), ..Default::default() })?;
+    // the data file sits next to its .state, wherever we are run from
+    let Some(plan) = dlstate::data_path(sf).and_then(PathPlan::from_temp_path) else {
+        eprintln!("Skipping {sf}: not a .state file");
+        return Ok(());
+    };
+
+    let mut meta = probe(client, auth, &st.url, ProbeMode::Auto, retry_policy(cfg)).await?;
+    meta.filename = plan.final_name.clone();
+    meta.ext_guessed = false;
+    // a login page is not "the remote file changed": keep the partial data
+    page::check_probe(client, auth, &meta, st.total).await?;
+
+    let changed = differs(&st.etag, &meta.etag)
+        || differs(&st.last_modified, &meta.last_modified)
+        || differs(&st.total, &meta.size);
+    let part = plan.temp_path();
+    if changed {
+        eprintln!("Remote file changed since {sf} was saved; starting {} over.", plan.final_name);
+        let _ = tokio::fs::remove_file(&part).await;
+        dlstate::remove_state(&part).await?;
+    } else {
+        // keep the saved validators so If-Range still guards the resume
+        meta.etag = meta.etag.or(st.etag);
+        meta.last_modified = meta.last_modified.or(st.last_modified);
+    }
 
+    // a digest given on the original command line still applies
+    let checksum = match st.checksum.as_deref().map(str::parse::<Checksum>) {
+        Some(Ok(c)) => Some(c),
+        Some(Err(e)) => {
+            eprintln!("Ignoring checksum saved in {sf}: {e}");
+            None
+        }
+        None => None,
+    };
+    if cfg.find_checksum && checksum.is_none() {
+        find_checksum(client, auth, &mut meta).await;
+    }
+
+    print_meta(&meta, checksum.as_ref());
+    let id = sched.add(&meta.final_url, Priority::Normal);
+    jobs.insert(id, Task {
+        link: st.url.clone(),
+        meta,
+        plan,
+        checksum,
+        mirrors: Vec::new(),
+        pieces: None,
+        sources: Vec::new(),
+        client: client.clone(),
+        parts: None,
+        input,
+        overwrite: false,
+        headers: st.headers,
+        saved_as: OnceLock::new(),
+    });
+    Ok(())
+}
+
+/// place: apply `on_conflict` when the target name is in use (a file, a .part, or
+/// another download of this run). None = skip; the bool asks for a fresh start.
+async fn place(
+    plan: PathPlan,
+    meta: &MetaInfo,
+    link: &str,
+    policy: OnConflict,
+    jobs: &Jobs,
+) -> Result<Option<(PathPlan, bool)>> {
+    if !in_use(&plan, jobs).await? {
+        return Ok(Some((plan, false)));
+    }
+    let ours = planned(&plan, jobs);
+    let resumable = !ours && same_download(&plan, meta, link).await;
+    let policy = match policy {
+        // another task of this run writes there: two writers on one .part corrupt both
+        _ if ours => OnConflict::Rename,
+        OnConflict::Ask => ask_conflict(&plan, resumable),
+        p => p,
+    };
+    match policy {
+        OnConflict::Resume | OnConflict::Ask if resumable => Ok(Some((plan, false))),
+        OnConflict::Resume | OnConflict::Ask | OnConflict::Rename => {
+            let mut n = 1;
+            let renamed = loop {
+                let p = plan.numbered(n);
+                if !in_use(&p, jobs).await? {
+                    break p;
+                }
+                n += 1;
+            };
+            println!("{} is taken; saving as {}", plan.final_path(), renamed.final_name);
+            Ok(Some((renamed, false)))
+        }
+        OnConflict::Skip => {
+            println!("Skipping {}: already exists", plan.final_path());
+            Ok(None)
+        }
+        OnConflict::Overwrite => Ok(Some((plan, true))),
+    }
+}
+
+/// ask_conflict: the `ask` policy; resume is only offered for our own unfinished download
+fn ask_conflict(plan: &PathPlan, resumable: bool) -> OnConflict {
+    let choices: &[(&str, OnConflict)] = if resumable {
+        &[("resume", OnConflict::Resume), ("rename", OnConflict::Rename), ("skip", OnConflict::Skip), ("overwrite", OnConflict::Overwrite)]
+    } else {
+        &[("rename", OnConflict::Rename), ("skip", OnConflict::Skip), ("overwrite", OnConflict::Overwrite)]
+    };
+    let words: Vec<&str> = choices.iter().map(|(w, _)| *w).collect();
+    match cli::choose(&format!("{} already exists.", plan.final_path()), &words) {
+        Some(i) => choices[i].1,
+        // no terminal: same as `resume`
+        None => OnConflict::Resume,
+    }
+}
+
+/// in_use: the final file or its .part exists, or another task of this run writes there.
+/// A stat that fails (no permission on the directory) is an error, not "taken":
+/// every other name would be "taken" too
+async fn in_use(plan: &PathPlan, jobs: &Jobs) -> Result<bool> {
+    Ok(planned(plan, jobs)
+        || tokio::fs::try_exists(plan.final_path()).await?
+        || tokio::fs::try_exists(plan.temp_path()).await?)
+}
+
+fn planned(plan: &PathPlan, jobs: &Jobs) -> bool {
+    jobs.values().any(|t| t.plan.final_path() == plan.final_path())
+}
+
+/// same_download: a .part whose .state names this URL (the link or where it led)
+/// with the same size and validators — the only data `resume` continues into
+async fn same_download(plan: &PathPlan, meta: &MetaInfo, link: &str) -> bool {
+    let part = plan.temp_path();
+    if !tokio::fs::try_exists(&part).await.unwrap_or(false) {
+        return false;
+    }
+    match dlstate::load_state_for(&part).await {
+        Ok(Some(st)) => {
+            (st.url == meta.final_url || st.url == link)
+                && !differs(&st.etag, &meta.etag)
+                && !differs(&st.last_modified, &meta.last_modified)
+                && !differs(&st.total, &meta.size)
+        }
+        _ => false,
+    }
+}
+
+/// find_checksum: sidecar lookup unless a header already gave us a digest
+async fn find_checksum(client: &Client, auth: &Auth, meta: &mut MetaInfo) {
+    if meta.checksum.is_none() {
+        inspect::find_sidecar_checksum(client, auth, meta).await;
+    }
+}
+
+/// print_meta: probe summary plus the digest the download will be checked against
+fn print_meta(meta: &MetaInfo, checksum: Option<&Checksum>) {
+    cli::print_meta(&meta.final_url, &meta.filename, meta.size, meta.accept_ranges);
+    match (checksum, &meta.checksum) {
+        (Some(c), _) => println!("Checksum : {c}"),
+        (None, Some(c)) => println!("Checksum : {c} ({})", meta.checksum_from.as_deref().unwrap_or("server")),
+        (None, None) => {}
+    }
+}
+
+/// probe: probe `url`, waiting out 429/503 (Retry-After) and network errors as the
+/// retry policy says; any other non-success status is final (its size is the error page's)
+async fn probe(client: &Client, auth: &Auth, url: &str, mode: ProbeMode, policy: RetryPolicy) -> Result<MetaInfo> {
+    let mut backoff = Backoff::new(policy);
+    loop {
+        let err = match inspect::probe_url(client, auth, url, mode).await {
+            Ok(meta) if meta.status.is_success() => return Ok(meta),
+            Ok(meta) => match retry::status_error(&meta.final_url, meta.status, &meta.headers) {
+                e @ DmError::HttpRetry { .. } => e,
+                _ => return Err(DmError::HttpStatus(format!("{}: {}", meta.final_url, meta.status))),
+            },
+            Err(e) => e,
+        };
+        retry::wait(&mut backoff, err, "Probe", &CancelToken::default()).await?;
+    }
+}
+
+/// reprobe: after DmError::Stale, ask `link` for a fresh final URL (expired CDN
+/// token, moved file); if the file itself changed, the partial data is thrown away
+async fn reprobe(client: &Client, opts: &DlOpts, link: &str, old: &MetaInfo, plan: &PathPlan) -> Result<MetaInfo> {
+    eprintln!("{}: link went stale; probing {link} again", plan.final_name);
+    let mut meta = probe(client, &opts.auth, link, ProbeMode::Auto, opts.retry).await?;
+    meta.filename = old.filename.clone();
+    meta.ext_guessed = old.ext_guessed;
+    page::check_probe(client, &opts.auth, &meta, old.size).await?;
+    if differs(&old.etag, &meta.etag) || differs(&old.last_modified, &meta.last_modified) || differs(&old.size, &meta.size) {
+        eprintln!("Remote file changed; starting {} over.", plan.final_name);
+        let part = plan.temp_path();
+        let _ = tokio::fs::remove_file(&part).await;
+        dlstate::remove_state(&part).await?;
+    }
+    Ok(meta)
+}
+
+/// drop_unstarted: a page came instead of the file before a single byte of it was
+/// saved; the empty .part (and its .state) would only make the next try look like a conflict
+async fn drop_unstarted(plan: &PathPlan) {
+    let part = plan.temp_path();
+    let empty = match dlstate::load_state_for(&part).await {
+        Ok(Some(st)) => st.done_bytes() == 0,
+        _ => tokio::fs::metadata(&part).await.is_ok_and(|m| m.len() == 0),
+    };
+    if empty {
+        let _ = tokio::fs::remove_file(&part).await;
+        let _ = dlstate::remove_state(&part).await;
+    }
+}
+
+/// retry_policy: attempt cap and deadline from config (and --max-attempts/--retry-deadline)
+fn retry_policy(cfg: &Config) -> RetryPolicy {
+    RetryPolicy::new(cfg.max_attempts, cfg.retry_deadline)
+}
+
+/// differs: both sides known and not equal
+fn differs<T: PartialEq>(saved: &Option<T>, now: &Option<T>) -> bool {
+    matches!((saved, now), (Some(a), Some(b)) if a != b)
+}
+
+/// parse_rate_opt: unset → no limit; set but unparsable → error naming the setting
+fn parse_rate_opt(what: &str, v: Option<&str>) -> Result<Option<u64>> {
+    match v {
+        None => Ok(None),
+        Some(s) => parse_rate(s)
+            .map(Some)
+            .ok_or_else(|| DmError::Other(format!("invalid {what}: {s:?} (expected e.g. 512K or 2M)"))),
+    }
+}
+
+/// run_config: `config show` / `config set KEY VALUE`
+fn run_config(action: &cli::ConfigAction, path: &str, mut cfg: Config) -> Result<()> {
+    match action {
+        cli::ConfigAction::Show => {
+            config::apply_env(&mut cfg)?;
+            println!("# {path}");
+            print!("{}", toml::to_string_pretty(&cfg).map_err(|e| DmError::Config(e.to_string()))?);
+        }
+        cli::ConfigAction::Set { key, value } => {
+            // env overrides are deliberately not applied: only the file is written back
+            config::set_key(&mut cfg, key, value)?;
+            config::save_config(path, &cfg)?;
+            println!("{key} saved to {path}");
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,165 @@
+//! Content-Disposition file names (RFC 6266 + RFC 8187 `filename*`)
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - `filename*=UTF-8''%D9%81%D8%A7%DB%8C%D9%84.zip` wins over `filename=`;
+//!   any charset encoding_rs knows (UTF-8, ISO-8859-1, windows-1256…) is decoded
+//! - `filename="…"` is unquoted (backslash escapes); common server habits are
+//!   tolerated: percent-encoded UTF-8, raw UTF-8 bytes, RFC 2047 `=?UTF-8?B?…?=`
+//! - the result is not yet safe as a path: callers pass it to util::filename::sanitize
+
+use base64::Engine as _;
+use encoding_rs::Encoding;
+use percent_encoding::percent_decode;
+
+/// filename: the name a Content-Disposition header value asks for, if any
+pub fn filename(value: &[u8]) -> Option<String> {
+    // header bytes are meant to be ASCII; servers that send raw UTF-8 (or Latin-1) still get read
+    let value = match std::str::from_utf8(value) {
+        Ok(s) => s.to_string(),
+        Err(_) => encoding_rs::WINDOWS_1252.decode(value).0.into_owned(),
+    };
+
+    let mut plain = None;
+    let mut extended = None;
+    for (name, raw) in params(&value) {
+        match name.as_str() {
+            "filename*" if extended.is_none() => extended = ext_value(&raw),
+            "filename" if plain.is_none() => plain = Some(raw),
+            _ => {}
+        }
+    }
+    extended
+        .or_else(|| plain.map(|p| decode_plain(&p)))
+        .filter(|s| !s.trim().is_empty())
+}
+
+/// params: `type; a=b; c="d;e"` → [(a, b), (c, d;e)] with names lowercased and quotes removed
+fn params(value: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let mut rest = value.split_once(';').map(|(_, r)| r).unwrap_or("");
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        if rest.is_empty() {
+            break;
+        }
+        // a bare token (`attachment; inline; filename=x`) has no value: skip it
+        let end = rest.find(';').unwrap_or(rest.len());
+        let Some(eq) = rest[..end].find('=') else {
+            rest = &rest[end..];
+            continue;
+        };
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let val = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut v = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, n)) = chars.next() {
+                            v.push(n);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => v.push(c),
+                }
+            }
+            rest = &quoted[end.min(quoted.len())..];
+            v
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let v = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            v
+        };
+        out.push((name, val));
+    }
+    out
+}
+
+/// ext_value: RFC 8187 `charset'language'pct-encoded`; None for an unknown charset
+fn ext_value(raw: &str) -> Option<String> {
+    let mut parts = raw.trim().trim_matches('"').splitn(3, '\'');
+    let (charset, _lang, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes: Vec<u8> = percent_decode(encoded.as_bytes()).collect();
+    let encoding = Encoding::for_label(charset.trim().as_bytes())?;
+    let (text, _, _) = encoding.decode(&bytes);
+    Some(text.into_owned())
+}
+
+/// decode_plain: `filename=` as servers really send it
+fn decode_plain(raw: &str) -> String {
+    if let Some(s) = encoded_word(raw) {
+        return s;
+    }
+    // "%D9%81.zip": decode only when the result is valid UTF-8
+    if raw.contains('%') {
+        if let Ok(s) = percent_decode(raw.as_bytes()).decode_utf8() {
+            return s.into_owned();
+        }
+    }
+    raw.to_string()
+}
+
+/// encoded_word: RFC 2047 `=?charset?B|Q?text?=` (as some mail-minded servers send)
+fn encoded_word(raw: &str) -> Option<String> {
+    let inner = raw.trim().strip_prefix("=?")?.strip_suffix("?=")?;
+    let mut parts = inner.splitn(3, '?');
+    let (charset, mode, text) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes = match mode {
+        "B" | "b" => base64::engine::general_purpose::STANDARD.decode(text).ok()?,
+        "Q" | "q" => {
+            let text = text.replace('_', " ").replace('=', "%");
+            percent_decode(text.as_bytes()).collect()
+        }
+        _ => return None,
+    };
+    let (text, _, _) = Encoding::for_label(charset.as_bytes())?.decode(&bytes);
+    Some(text.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_table() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("attachment; filename=plain.zip", Some("plain.zip")),
+            ("attachment; filename=\"a \\\"b\\\";c.zip\"", Some("a \"b\";c.zip")),
+            // filename* wins, wherever it stands
+            ("attachment; filename=\"fallback.zip\"; filename*=UTF-8''%E2%82%AC%20rates.zip", Some("€ rates.zip")),
+            ("attachment; filename*=UTF-8''%E2%82%AC.zip; filename=fallback.zip", Some("€.zip")),
+            ("attachment; filename*=iso-8859-1'en'%A3%20rates.txt", Some("£ rates.txt")),
+            // the example from the request
+            ("attachment; filename*=UTF-8''%D9%81%D8%A7%DB%8C%D9%84.zip", Some("فایل.zip")),
+            ("attachment; filename*=\"UTF-8''%D9%81%D8%A7%DB%8C%D9%84.zip\"", Some("فایل.zip")),
+            // an unknown charset falls back to filename=
+            ("attachment; filename*=x-klingon''abc.zip; filename=ok.zip", Some("ok.zip")),
+            ("attachment; filename*=x-klingon''abc.zip", None),
+            // RFC 2047 words
+            ("attachment; filename=\"=?UTF-8?B?2YHYp9uM2YQuemlw?=\"", Some("فایل.zip")),
+            ("attachment; filename=\"=?ISO-8859-1?Q?caf=E9_menu.pdf?=\"", Some("café menu.pdf")),
+            // percent-encoded and raw UTF-8 in filename=
+            ("attachment; filename=%D9%81.zip", Some("ف.zip")),
+            ("attachment; filename=\"فایل.zip\"", Some("فایل.zip")),
+            // bare tokens before the parameter
+            ("attachment; inline; filename=x.zip", Some("x.zip")),
+            ("attachment;; FileName=\"y.zip\"", Some("y.zip")),
+            ("attachment", None),
+            ("attachment; filename=\"\"", None),
+        ];
+        for &(header, want) in cases {
+            assert_eq!(filename(header.as_bytes()).as_deref(), want, "{header}");
+        }
+    }
+
+    #[test]
+    fn latin1_header_bytes() {
+        assert_eq!(filename(b"attachment; filename=\"caf\xe9.txt\"").as_deref(), Some("café.txt"));
+    }
+}
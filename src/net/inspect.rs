@@ -5,13 +5,25 @@
 //! - pub struct MetaInfo
 //! - pub fn probe_url(...)
 //! - pub fn print_table(...)
+//! - pub fn find_sidecar_checksum(...)
+//!
+//! Expected digests come from `Repr-Digest`/`Digest` headers (always) and,
+//! when asked, from sidecar files next to the URL (`file.sha256`, `SHA256SUMS`, …).
 
 use reqwest::{Client, StatusCode};
 use reqwest::header::{
-    HeaderMap, CONTENT_DISPOSITION, CONTENT_LENGTH, ACCEPT_RANGES, CONTENT_RANGE, ETAG, LAST_MODIFIED,
+    HeaderMap, CONTENT_DISPOSITION, CONTENT_LENGTH, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    LAST_MODIFIED,
 };
-use content_disposition::{parse_content_disposition, ParsedContentDisposition};
 use url::Url;
+use crate::engine::prelude::DmError;
+use crate::http::auth::Auth;
+use crate::util::checksum::{self, Algo, Checksum};
+use crate::util::{filename, mime};
+use super::disposition;
+
+/// Sidecar checksum files are tiny; anything bigger is not one.
+const MAX_SIDECAR: usize = 1 << 20;
 
 #[derive(Debug, Clone, Copy)]
 pub enum ProbeMode {
@@ -29,20 +41,29 @@ pub struct MetaInfo {
     pub status: StatusCode,
     pub headers: HeaderMap,
     pub filename: String,
+    /// Content-Type without parameters, lowercase
+    pub content_type: Option<String>,
+    /// the extension of `filename` is our guess (from Content-Type, or none at all);
+    /// the first bytes of the file get the last word
+    pub ext_guessed: bool,
     pub size: Option<u64>,
     pub accept_ranges: bool,
     pub etag: Option<String>,
     pub last_modified: Option<String>,
+    /// expected digest published by the server, if any
+    pub checksum: Option<Checksum>,
+    /// where `checksum` came from (header name or sidecar URL)
+    pub checksum_from: Option<String>,
 }
 
 /// probe_url: performs HEAD or GET 0-0 (based on mode) and returns metadata of the *final* response
-pub async fn probe_url(client: &Client, url: &str, mode: ProbeMode) -> Result<MetaInfo, String> {
+pub async fn probe_url(client: &Client, auth: &Auth, url: &str, mode: ProbeMode) -> Result<MetaInfo, DmError> {
     let resp = match mode {
-        ProbeMode::Head => super::request::head(client, url).await?,
-        ProbeMode::GetRange0 => super::request::get_range0(client, url).await?,
-        ProbeMode::Auto => match super::request::head(client, url).await {
+        ProbeMode::Head => super::request::head(client, auth, url).await?,
+        ProbeMode::GetRange0 => super::request::get_range0(client, auth, url).await?,
+        ProbeMode::Auto => match super::request::head(client, auth, url).await {
             Ok(r) if r.status().is_success() => r,
-            Ok(_) | Err(_) => super::request::get_range0(client, url).await?,
+            Ok(_) | Err(_) => super::request::get_range0(client, auth, url).await?,
         },
     };
 
@@ -50,7 +71,12 @@ pub async fn probe_url(client: &Client, url: &str, mode: ProbeMode) -> Result<Me
     let final_url = resp.url().to_string();
     let headers = resp.headers().clone();
 
-    let filename = infer_filename(&final_url, &headers);
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(mime::essence)
+        .filter(|t| !t.is_empty());
+    let (filename, ext_guessed) = add_extension(infer_filename(&final_url, &headers), content_type.as_deref());
     let size = parse_size(&headers);
     let accept_ranges = supports_range(&headers);
     let etag = headers
@@ -61,24 +87,72 @@ pub async fn probe_url(client: &Client, url: &str, mode: ProbeMode) -> Result<Me
         .get(LAST_MODIFIED)
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_string());
+    let (checksum, checksum_from) = match header_checksum(&headers) {
+        Some((c, from)) => (Some(c), Some(from.to_string())),
+        None => (None, None),
+    };
 
     Ok(MetaInfo {
         final_url,
         status,
         headers,
         filename,
+        content_type,
+        ext_guessed,
         size,
         accept_ranges,
         etag,
         last_modified,
+        checksum,
+        checksum_from,
     })
 }
 
+/// find_sidecar_checksum: look for `<url>.sha256` & co., then `SHA256SUMS` & co.
+/// in the same directory, and record the first digest that names this file.
+/// Best effort: a missing or unreadable sidecar just means "no checksum".
+pub async fn find_sidecar_checksum(client: &Client, auth: &Auth, meta: &mut MetaInfo) {
+    let Ok(mut base) = Url::parse(&meta.final_url) else { return };
+    base.set_query(None);
+    base.set_fragment(None);
+    let Some(name) = base
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_encoding::percent_decode_str(s).decode_utf8_lossy().into_owned())
+    else {
+        return;
+    };
+
+    const ALGOS: [(Algo, &str, &str); 4] = [
+        (Algo::Sha256, "sha256", "SHA256SUMS"),
+        (Algo::Sha512, "sha512", "SHA512SUMS"),
+        (Algo::Sha1, "sha1", "SHA1SUMS"),
+        (Algo::Md5, "md5", "MD5SUMS"),
+    ];
+    let per_file = ALGOS.iter().map(|&(algo, ext, _)| (algo, format!("{base}.{ext}")));
+    let sums = ALGOS
+        .iter()
+        .filter_map(|&(algo, _, list)| base.join(list).ok().map(|u| (algo, u.to_string())));
+
+    for (algo, url) in per_file.chain(sums) {
+        let Some(text) = fetch_small_text(client, auth, &url).await else { continue };
+        if let Some(c) = checksum::from_sidecar(&text, algo, &name) {
+            meta.checksum = Some(c);
+            meta.checksum_from = Some(url);
+            return;
+        }
+    }
+}
+
 /// print_table: pretty-print key info and all headers
 pub fn print_table(meta: &MetaInfo) {
     println!("Status   : {}", meta.status);
     println!("FinalURL : {}", meta.final_url);
     println!("Filename : {}", meta.filename);
+    if let Some(t) = &meta.content_type {
+        println!("Type     : {t}");
+    }
     match meta.size {
         Some(n) => println!("Size     : {} bytes", n),
         None => println!("Size     : (unknown)"),
@@ -97,6 +171,9 @@ pub fn print_table(meta: &MetaInfo) {
     if let Some(lm) = &meta.last_modified {
         println!("Last-Mod : {lm}");
     }
+    if let Some(c) = &meta.checksum {
+        println!("Checksum : {c} ({})", meta.checksum_from.as_deref().unwrap_or("?"));
+    }
 
     println!("\n+--------------------------+------------------------------------------+");
     println!("| {:24} | {:40} |", "Header", "Value");
@@ -111,24 +188,70 @@ pub fn print_table(meta: &MetaInfo) {
 
 // ---------- private helpers ----------
 
+/// infer_filename: Content-Disposition name (`filename*` first), else the last URL
+/// segment percent-decoded; either way sanitized, since a server may send
+/// "../../.bashrc" or an absolute path
 fn infer_filename(url: &str, headers: &HeaderMap) -> String {
-    if let Some(v) = headers
-        .get(CONTENT_DISPOSITION)
-        .and_then(|h| h.to_str().ok())
-    {
-        let parsed: ParsedContentDisposition = parse_content_disposition(v);
-        if let Some((fname, _)) = parsed.filename() {
-            let t = fname.trim().trim_matches('"').to_string();
-            if !t.is_empty() {
-                return t;
-            }
-        }
+    if let Some(name) = headers.get(CONTENT_DISPOSITION).and_then(|h| disposition::filename(h.as_bytes())) {
+        return filename::sanitize(&name);
     }
     Url::parse(url)
         .ok()
-        .and_then(|u| u.path_segments()?.last().map(|s| s.to_string()))
+        .and_then(|u| {
+            let seg = u.path_segments()?.next_back()?;
+            Some(percent_encoding::percent_decode_str(seg).decode_utf8_lossy().into_owned())
+        })
         .filter(|s| !s.is_empty())
-        .unwrap_or_else(|| "download.bin".to_string())
+        .map(|s| filename::sanitize(&s))
+        .unwrap_or_else(|| filename::FALLBACK_NAME.to_string())
+}
+
+/// add_extension: a name without an extension (or our "download.bin") gets one from
+/// Content-Type. `true` when the extension is not the server's own.
+fn add_extension(name: String, content_type: Option<&str>) -> (String, bool) {
+    let fallback = name == filename::FALLBACK_NAME;
+    if !fallback && mime::extension(&name).is_some() {
+        return (name, false);
+    }
+    match content_type.and_then(mime::extension_for) {
+        Some(ext) => {
+            let stem = if fallback { "download" } else { name.as_str() };
+            (filename::sanitize(&format!("{stem}.{ext}")), true)
+        }
+        None => (name, true),
+    }
+}
+
+/// header_checksum: RFC 9530 `Repr-Digest` first, then the older RFC 3230 `Digest`
+fn header_checksum(headers: &HeaderMap) -> Option<(Checksum, &'static str)> {
+    ["repr-digest", "digest"].into_iter().find_map(|name| {
+        let v = headers.get(name)?.to_str().ok()?;
+        checksum::from_digest_header(v).map(|c| (c, if name == "digest" { "Digest" } else { "Repr-Digest" }))
+    })
+}
+
+/// fetch_small_text: body of a successful, non-HTML response no bigger than MAX_SIDECAR
+async fn fetch_small_text(client: &Client, auth: &Auth, url: &str) -> Option<String> {
+    let mut resp = auth.send(client.get(url)).await.ok()?;
+    if !resp.status().is_success() || resp.content_length().is_some_and(|n| n > MAX_SIDECAR as u64) {
+        return None;
+    }
+    let html = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| t.contains("html"));
+    if html {
+        return None;
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await.ok()? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_SIDECAR {
+            return None;
+        }
+    }
+    String::from_utf8(body).ok()
 }
 
 fn parse_size(headers: &HeaderMap) -> Option<u64> {
@@ -2,4 +2,7 @@
 //! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
 pub mod request;
 pub mod inspect;
-pub mod url;
\ No newline at end of file
+pub mod url;
+pub mod disposition;
+pub mod page;
+pub mod retry;
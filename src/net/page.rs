@@ -0,0 +1,227 @@
+//! Web pages served instead of the file: expired links, login walls, error pages
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! Checked three times, each before anything on disk is touched:
+//! - probe: `text/html` for a file named `.exe`/`.zip`/…, or a size far below
+//!   what we already know (metalink, .state, first source); the body is read to
+//!   confirm it is HTML and to name the page by its <title>
+//! - every response: HTML where the probe saw the real file
+//! - first chunk at offset 0: HTML markup whatever the headers claim
+//!
+//! The result is DmError::WrongContent; it is not retried (waiting does not log you in).
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response};
+
+use crate::engine::prelude::*;
+use crate::http::auth::Auth;
+use crate::net::inspect::MetaInfo;
+use crate::util::{format::format_size, mime};
+
+/// How much of a page is read to find its title
+const MAX_PAGE: usize = 64 * 1024;
+
+/// check_probe: the probed response must look like the file, not like a page about it
+pub async fn check_probe(client: &Client, auth: &Auth, meta: &MetaInfo, expected: Option<u64>) -> Result<()> {
+    let small = matches!((meta.size, expected), (Some(got), Some(want)) if far_below(got, want));
+    let html = meta.content_type.as_deref().is_some_and(is_html_type);
+    let named_file = !meta.ext_guessed && !html_name(&meta.filename);
+
+    if html && (named_file || small) {
+        // some servers label everything text/html: only a real page is refused
+        if let Ok(resp) = auth.send(client.get(&meta.final_url)).await {
+            if let Some(err) = read_page(&meta.final_url, resp).await {
+                return Err(err);
+            }
+        }
+    }
+    if let (true, Some(got), Some(want)) = (small, meta.size, expected) {
+        return Err(DmError::WrongContent {
+            url: meta.final_url.clone(),
+            what: format!(
+                "{} of {} (expected {})",
+                format_size(got),
+                meta.content_type.as_deref().unwrap_or("data"),
+                format_size(want)
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// check_response: a download response that turned into HTML while the probe
+/// saw the real file; the page is read for its title
+pub async fn check_response(meta: &MetaInfo, url: &str, resp: Response) -> Result<Response> {
+    let html = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| is_html_type(&mime::essence(t)));
+    if !html || wants_page(meta) {
+        return Ok(resp);
+    }
+    match read_page(url, resp).await {
+        Some(err) => Err(err),
+        // labelled HTML but is not: the probe already vouched for this file
+        None => Err(DmError::WrongContent { url: url.to_string(), what: "a text/html response".into() }),
+    }
+}
+
+/// check_first_chunk: the first bytes of the file must not be HTML markup
+pub fn check_first_chunk(meta: &MetaInfo, url: &str, chunk: &[u8]) -> Result<()> {
+    if wants_page(meta) || !looks_like_html(chunk) {
+        return Ok(());
+    }
+    Err(DmError::WrongContent { url: url.to_string(), what: describe(title(chunk)) })
+}
+
+/// wants_page: the download itself is a web page (probed as HTML, or named .html)
+fn wants_page(meta: &MetaInfo) -> bool {
+    meta.content_type.as_deref().is_some_and(is_html_type) || html_name(&meta.filename)
+}
+
+/// read_page: up to MAX_PAGE bytes of `resp`; Some(error naming the title) if it is HTML
+async fn read_page(url: &str, mut resp: Response) -> Option<DmError> {
+    let mut body = Vec::new();
+    while let Ok(Some(chunk)) = resp.chunk().await {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PAGE {
+            break;
+        }
+    }
+    looks_like_html(&body).then(|| DmError::WrongContent { url: url.to_string(), what: describe(title(&body)) })
+}
+
+fn describe(title: Option<String>) -> String {
+    match title {
+        Some(t) => format!("a web page titled \"{t}\""),
+        None => "a web page".to_string(),
+    }
+}
+
+/// far_below: less than a tenth of what was expected
+fn far_below(got: u64, expected: u64) -> bool {
+    got.saturating_mul(10) < expected
+}
+
+fn is_html_type(essence: &str) -> bool {
+    matches!(essence, "text/html" | "application/xhtml+xml")
+}
+
+fn html_name(name: &str) -> bool {
+    mime::extension(name).is_some_and(|e| {
+        matches!(e.to_ascii_lowercase().as_str(), "html" | "htm" | "xhtml" | "shtml" | "php" | "asp" | "aspx" | "jsp")
+    })
+}
+
+/// looks_like_html: markup at the start (after a BOM and whitespace)
+fn looks_like_html(head: &[u8]) -> bool {
+    let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let start = head.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(head.len());
+    let lower = head[start..head.len().min(start + 1024)].to_ascii_lowercase();
+    const TAGS: [&[u8]; 7] = [b"<!doctype html", b"<html", b"<head", b"<body", b"<title", b"<meta", b"<script"];
+    if TAGS.iter().any(|t| lower.starts_with(t)) {
+        return true;
+    }
+    // a comment or XML prolog first: still a page if an <html> tag follows
+    (lower.starts_with(b"<!--") || lower.starts_with(b"<?xml")) && lower.windows(5).any(|w| w == b"<html")
+}
+
+/// title: text of <title>…</title>, entities decoded, whitespace collapsed, ≤ 100 chars
+fn title(body: &[u8]) -> Option<String> {
+    let lower = body.to_ascii_lowercase();
+    let find = |hay: &[u8], needle: &[u8], from: usize| {
+        hay.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|i| i + from)
+    };
+    let open = find(&lower, b"<title", 0)?;
+    let start = find(&lower, b">", open)? + 1;
+    let end = find(&lower, b"</title", start).unwrap_or(body.len());
+    let raw = String::from_utf8_lossy(&body[start..end]);
+    let text = raw
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let text: String = text.chars().take(100).collect();
+    (!text.is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderMap;
+    use reqwest::StatusCode;
+
+    fn meta(filename: &str, content_type: Option<&str>) -> MetaInfo {
+        MetaInfo {
+            final_url: format!("https://dl.example/{filename}"),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            filename: filename.to_string(),
+            content_type: content_type.map(str::to_string),
+            ext_guessed: false,
+            size: None,
+            accept_ranges: true,
+            etag: None,
+            last_modified: None,
+            checksum: None,
+            checksum_from: None,
+        }
+    }
+
+    #[test]
+    fn html_at_the_start() {
+        assert!(looks_like_html(b"<!DOCTYPE html><html><head>"));
+        assert!(looks_like_html(b"<!doctype HTML>\n<html>"));
+        assert!(looks_like_html(b"<!DocType Html>"));
+        assert!(looks_like_html(b"\xef\xbb\xbf<html lang=\"fa\">"));
+        assert!(looks_like_html(b"\xef\xbb\xbf \r\n\t  <HEAD><TITLE>x</TITLE>"));
+        assert!(looks_like_html(b"\n\n\n<script>location='/login'</script>"));
+        assert!(looks_like_html(b"<?xml version=\"1.0\"?>\n<html xmlns=\"http://www.w3.org/1999/xhtml\">"));
+        assert!(looks_like_html(b"<!-- generated -->\n<html>"));
+    }
+
+    #[test]
+    fn not_html() {
+        assert!(!looks_like_html(b""));
+        assert!(!looks_like_html(b"   \n"));
+        assert!(!looks_like_html(b"PK\x03\x04<html>"));
+        assert!(!looks_like_html(b"{\"html\": \"<html>\"}"));
+        assert!(!looks_like_html(b"<?xml version=\"1.0\"?><metalink/>"));
+        assert!(!looks_like_html(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"));
+        // a binary file with markup somewhere inside
+        let mut bin = b"%PDF-1.7\n".to_vec();
+        bin.extend(std::iter::repeat_n(0u8, 4096));
+        bin.extend_from_slice(b"<html><title>embedded</title></html>");
+        assert!(!looks_like_html(&bin));
+        // a comment first: the <html> tag has to be within the sniff window
+        let mut late = b"<!--".to_vec();
+        late.extend(std::iter::repeat_n(b'x', 2048));
+        late.extend_from_slice(b"--><html>");
+        assert!(!looks_like_html(&late));
+    }
+
+    #[test]
+    fn titles() {
+        let page = b"<html><head><TITLE>\n  Session   expired &amp; gone &lt;3\n</TITLE></head>";
+        assert_eq!(title(page).as_deref(), Some("Session expired & gone <3"));
+        assert_eq!(title("<title>ورود به حساب</title>".as_bytes()).as_deref(), Some("ورود به حساب"));
+        assert_eq!(title(b"<title>   </title>"), None);
+        assert_eq!(title(b"<html><body>no title</body></html>"), None);
+        assert_eq!(title(format!("<title>{}</title>", "a".repeat(300)).as_bytes()).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn first_chunk_check() {
+        let page = b"<!DOCTYPE html><title>Please sign in</title>";
+        let err = check_first_chunk(&meta("setup.exe", None), "u", page).unwrap_err().to_string();
+        assert!(err.contains("Please sign in"), "{err}");
+        assert!(check_first_chunk(&meta("setup.exe", None), "u", b"MZ\x90\x00").is_ok());
+        // asking for a page is fine
+        assert!(check_first_chunk(&meta("index.html", None), "u", page).is_ok());
+        assert!(check_first_chunk(&meta("download", Some("text/html")), "u", page).is_ok());
+    }
+}
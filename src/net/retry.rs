@@ -0,0 +1,257 @@
+//! Retry policy: which failures are worth another try, and how long to wait
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - 408/425/429/5xx (gateway/unavailable), timeouts, resets, short reads → retry
+//! - 403/404/410/412 on a link that probed fine → re-probe (expired CDN token,
+//!   file replaced); the runner asks for a fresh final URL instead of hammering
+//! - anything else (400, 401 after auth, 416 we could not fix…) → fail now
+//! - `Retry-After` (seconds or an HTTP date) wins over our own backoff, but
+//!   never beyond RETRY_MAX_DELAY_SECS (a hostile header must not park a job);
+//!   otherwise 1s, 2s, 4s … capped at RETRY_MAX_DELAY_SECS, jittered to [d/2, d]
+//! - `max_attempts` counts the first try; `deadline` bounds the time spent on
+//!   one stall (progress resets both)
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::engine::consts::{MAX_ATTEMPTS, RETRY_BASE_MS, RETRY_DEADLINE_SECS, RETRY_MAX_DELAY_SECS};
+use crate::engine::prelude::*;
+use crate::engine::types::CancelToken;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Retry,
+    Fail,
+    Reprobe,
+}
+
+/// classify_status: what a non-success status means for the download
+pub fn classify_status(status: StatusCode) -> Verdict {
+    match status.as_u16() {
+        408 | 425 | 429 | 500 | 502 | 503 | 504 => Verdict::Retry,
+        403 | 404 | 410 | 412 => Verdict::Reprobe,
+        _ => Verdict::Fail,
+    }
+}
+
+/// classify: verdict for an error coming out of a request or a stream
+pub fn classify(err: &DmError) -> Verdict {
+    match err {
+        DmError::Network(_) | DmError::Truncated { .. } | DmError::HttpRetry { .. } => Verdict::Retry,
+        DmError::Stale(_) => Verdict::Reprobe,
+        _ => Verdict::Fail,
+    }
+}
+
+/// status_error: the error for a non-success response, carrying Retry-After when retryable
+pub fn status_error(url: &str, status: StatusCode, headers: &HeaderMap) -> DmError {
+    let msg = format!("{url}: {status}");
+    match classify_status(status) {
+        Verdict::Retry => DmError::HttpRetry { msg, retry_after: retry_after(headers) },
+        Verdict::Reprobe => DmError::Stale(msg),
+        Verdict::Fail => DmError::HttpStatus(msg),
+    }
+}
+
+/// from_reqwest: a failed send. Bad URLs and redirect loops will not heal by
+/// waiting; connect errors, timeouts and resets might.
+pub fn from_reqwest(e: reqwest::Error) -> DmError {
+    if e.is_builder() || e.is_redirect() {
+        DmError::Other(e.to_string())
+    } else {
+        DmError::Network(e.to_string())
+    }
+}
+
+/// retry_after: `Retry-After: 120` or `Retry-After: Wed, 21 Oct 2026 07:28:00 GMT`,
+/// clamped to RETRY_MAX_DELAY_SECS
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let v = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let wait = match v.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = httpdate::parse_http_date(v).ok()?;
+            // a date in the past means "now"
+            at.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(wait.min(Duration::from_secs(RETRY_MAX_DELAY_SECS)))
+}
+
+/// RetryPolicy: attempt cap and deadline (config `max_attempts`, `retry_deadline`)
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// tries per stall, the first one included (≥1)
+    pub max_attempts: u32,
+    /// None = no time limit
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(MAX_ATTEMPTS, RETRY_DEADLINE_SECS)
+    }
+}
+
+impl RetryPolicy {
+    /// new: `deadline_secs` 0 = no deadline
+    pub fn new(max_attempts: u32, deadline_secs: u64) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            deadline: (deadline_secs > 0).then(|| Duration::from_secs(deadline_secs)),
+        }
+    }
+}
+
+/// Backoff: retry bookkeeping of one request loop (a single stream, one segment, a probe)
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    /// failures since the last progress
+    attempt: u32,
+    /// when the current stall began
+    since: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Backoff { policy, attempt: 0, since: None }
+    }
+
+    /// reset: bytes arrived, so the next failure is a new stall
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.since = None;
+    }
+
+    /// next_delay: how long to wait before trying again after `err`;
+    /// None when it should not be retried (or attempts/deadline are used up)
+    pub fn next_delay(&mut self, err: &DmError) -> Option<Duration> {
+        if classify(err) != Verdict::Retry {
+            return None;
+        }
+        let since = *self.since.get_or_insert_with(Instant::now);
+        self.attempt += 1;
+        if self.attempt >= self.policy.max_attempts {
+            return None;
+        }
+        let delay = match err {
+            DmError::HttpRetry { retry_after: Some(d), .. } => (*d).min(Duration::from_secs(RETRY_MAX_DELAY_SECS)),
+            _ => jittered(self.attempt),
+        };
+        match self.policy.deadline {
+            Some(limit) if since.elapsed().saturating_add(delay) > limit => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// wait: sleep before the next try, or hand `err` back when it is not retried.
+/// A cancel during the sleep ends it early with the usual "cancelled" error.
+pub async fn wait(backoff: &mut Backoff, err: DmError, label: &str, cancel: &CancelToken) -> Result<()> {
+    let Some(delay) = backoff.next_delay(&err) else {
+        return Err(err);
+    };
+    eprintln!(
+        "{label}: {err}. Retrying in {:.1}s (attempt {}/{})…",
+        delay.as_secs_f64(),
+        backoff.attempt + 1,
+        backoff.policy.max_attempts
+    );
+    let start = Instant::now();
+    while start.elapsed() < delay {
+        if cancel.is_cancelled() {
+            return Err(DmError::Other("cancelled".into()));
+        }
+        let left = delay.saturating_sub(start.elapsed());
+        tokio::time::sleep(left.min(Duration::from_millis(250))).await;
+    }
+    Ok(())
+}
+
+/// jittered: base·2^(n-1), capped, then a random point in [d/2, d] so that many
+/// connections hit by the same outage do not come back in lockstep
+fn jittered(attempt: u32) -> Duration {
+    let exp = RETRY_BASE_MS.saturating_mul(1 << (attempt - 1).min(16));
+    let full = exp.min(RETRY_MAX_DELAY_SECS * 1000);
+    // RandomState is seeded per instance: good enough for jitter, no rand crate needed
+    let r = std::collections::hash_map::RandomState::new().hash_one(attempt);
+    Duration::from_millis(full / 2 + r % (full / 2 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        h
+    }
+
+    #[test]
+    fn retry_after_seconds_and_dates() {
+        assert_eq!(retry_after(&headers("7")), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after(&headers(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn retry_after_is_clamped() {
+        let cap = Some(Duration::from_secs(RETRY_MAX_DELAY_SECS));
+        assert_eq!(retry_after(&headers("18446744073709551615")), cap);
+        assert_eq!(retry_after(&headers("86400")), cap);
+        assert_eq!(retry_after(&headers("Fri, 31 Dec 9999 23:59:59 GMT")), cap);
+    }
+
+    #[test]
+    fn huge_retry_after_does_not_overflow() {
+        let mut b = Backoff::new(RetryPolicy::new(3, 0));
+        let err = DmError::HttpRetry { msg: "x".into(), retry_after: Some(Duration::MAX) };
+        assert_eq!(b.next_delay(&err), Some(Duration::from_secs(RETRY_MAX_DELAY_SECS)));
+
+        let mut b = Backoff::new(RetryPolicy::new(3, 10));
+        let err = DmError::HttpRetry { msg: "x".into(), retry_after: Some(Duration::MAX) };
+        assert_eq!(b.next_delay(&err), None);
+    }
+
+    #[test]
+    fn status_classes() {
+        for s in [408, 425, 429, 500, 502, 503, 504] {
+            assert_eq!(classify_status(StatusCode::from_u16(s).unwrap()), Verdict::Retry, "{s}");
+        }
+        for s in [403, 404, 410, 412] {
+            assert_eq!(classify_status(StatusCode::from_u16(s).unwrap()), Verdict::Reprobe, "{s}");
+        }
+        for s in [400, 401, 405, 416, 501] {
+            assert_eq!(classify_status(StatusCode::from_u16(s).unwrap()), Verdict::Fail, "{s}");
+        }
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let cap = RETRY_MAX_DELAY_SECS * 1000;
+        for attempt in 1..=40 {
+            let full = (RETRY_BASE_MS << (attempt - 1).min(16)).min(cap);
+            for _ in 0..20 {
+                let d = jittered(attempt).as_millis() as u64;
+                assert!(d >= full / 2 && d <= full, "attempt {attempt}: {d} not in [{}, {full}]", full / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn attempts_are_capped() {
+        let mut b = Backoff::new(RetryPolicy::new(3, 0));
+        let err = DmError::Network("reset".into());
+        assert!(b.next_delay(&err).is_some());
+        assert!(b.next_delay(&err).is_some());
+        assert!(b.next_delay(&err).is_none());
+        assert!(Backoff::new(RetryPolicy::default()).next_delay(&DmError::Other("x".into())).is_none());
+    }
+}
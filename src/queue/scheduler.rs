@@ -0,0 +1,313 @@
+//! Download queue + scheduler (max_concurrent, per_host_limit, priorities)
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - add(): queue a job, returns its JobId
+//! - pause()/resume()/cancel(): control a single job (queued or running)
+//! - run(): drive the queue until nothing is queued or running
+//!
+//! The scheduler does not know how to download; `run` gets a runner closure
+//! that receives the job and a CancelToken it must honor.
+
+use crate::engine::config::Config;
+use crate::engine::prelude::*;
+use crate::engine::types::{CancelToken, JobId};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::{self, JoinSet};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+/// Job: what the runner gets for each scheduled download
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub url: String,
+    pub priority: Priority,
+}
+
+pub type JobFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Pause,
+    Cancel,
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    host: String,
+    seq: u64,
+    status: JobStatus,
+    cancel: CancelToken,
+    stop: Option<StopReason>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    jobs: HashMap<JobId, Entry>,
+}
+
+/// Scheduler: cheap to clone; clones share the same queue.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    inner: Arc<Mutex<Inner>>,
+    wake: Arc<Notify>,
+    max_concurrent: usize,
+    per_host_limit: usize,
+}
+
+impl Scheduler {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            inner: Arc::default(),
+            wake: Arc::default(),
+            max_concurrent: cfg.max_concurrent.max(1),
+            per_host_limit: cfg.per_host_limit.max(1),
+        }
+    }
+
+    /// add: queue `url`; jobs of equal priority start in insertion order
+    pub fn add(&self, url: &str, priority: Priority) -> JobId {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = JobId(inner.next_id);
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_ascii_lowercase()))
+            .unwrap_or_default();
+        let entry = Entry {
+            job: Job { id, url: url.to_string(), priority },
+            host,
+            seq: id.0,
+            status: JobStatus::Queued,
+            cancel: CancelToken::new(),
+            stop: None,
+        };
+        inner.jobs.insert(id, entry);
+        drop(inner);
+        self.wake.notify_one();
+        id
+    }
+
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        self.inner.lock().unwrap().jobs.get(&id).map(|e| e.status.clone())
+    }
+
+    /// jobs: snapshot of every job and its status, in insertion order
+    pub fn jobs(&self) -> Vec<(Job, JobStatus)> {
+        let inner = self.inner.lock().unwrap();
+        let mut v: Vec<&Entry> = inner.jobs.values().collect();
+        v.sort_by_key(|e| e.seq);
+        v.into_iter().map(|e| (e.job.clone(), e.status.clone())).collect()
+    }
+
+    pub fn set_priority(&self, id: JobId, priority: Priority) -> bool {
+        match self.inner.lock().unwrap().jobs.get_mut(&id) {
+            Some(e) => {
+                e.job.priority = priority;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// pause: a queued job is held back; a running one is stopped (state is saved by the engine)
+    pub fn pause(&self, id: JobId) -> bool {
+        self.stop(id, StopReason::Pause)
+    }
+
+    /// cancel: drop the job for good
+    pub fn cancel(&self, id: JobId) -> bool {
+        self.stop(id, StopReason::Cancel)
+    }
+
+    /// resume: put a paused job back in the queue
+    pub fn resume(&self, id: JobId) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(e) = inner.jobs.get_mut(&id) else { return false };
+        if e.status != JobStatus::Paused {
+            return false;
+        }
+        e.status = JobStatus::Queued;
+        e.cancel = CancelToken::new();
+        e.stop = None;
+        drop(inner);
+        self.wake.notify_one();
+        true
+    }
+
+    /// pause_all: used on Ctrl+C — stop everything, keep it resumable
+    pub fn pause_all(&self) {
+        let ids: Vec<JobId> = self.inner.lock().unwrap().jobs.keys().copied().collect();
+        for id in ids {
+            self.pause(id);
+        }
+    }
+
+    fn stop(&self, id: JobId, reason: StopReason) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(e) = inner.jobs.get_mut(&id) else { return false };
+        let changed = match e.status {
+            JobStatus::Queued | JobStatus::Paused => {
+                e.status = match reason {
+                    StopReason::Pause => JobStatus::Paused,
+                    StopReason::Cancel => JobStatus::Cancelled,
+                };
+                true
+            }
+            JobStatus::Running => {
+                e.stop = Some(reason);
+                e.cancel.cancel();
+                true
+            }
+            _ => false,
+        };
+        drop(inner);
+        self.wake.notify_one();
+        changed
+    }
+
+    /// run: start jobs as slots free up until nothing is queued or running.
+    /// Paused jobs stay paused; call `resume` + `run` again to continue them.
+    pub async fn run<F>(&self, runner: F) -> Vec<(Job, JobStatus)>
+    where
+        F: Fn(Job, CancelToken) -> JobFuture,
+    {
+        let mut set: JoinSet<Result<()>> = JoinSet::new();
+        // a panicking task only leaves its tokio task id behind
+        let mut tasks: HashMap<task::Id, JobId> = HashMap::new();
+        loop {
+            for (job, cancel) in self.take_ready() {
+                let id = job.id;
+                let handle = set.spawn(runner(job, cancel));
+                tasks.insert(handle.id(), id);
+            }
+            if set.is_empty() {
+                break;
+            }
+            tokio::select! {
+                Some(joined) = set.join_next_with_id() => match joined {
+                    Ok((task, res)) => {
+                        if let Some(id) = tasks.remove(&task) {
+                            self.finish(id, res);
+                        }
+                    }
+                    Err(err) => {
+                        if let Some(id) = tasks.remove(&err.id()) {
+                            let why = if err.is_panic() { "panicked" } else { "aborted" };
+                            self.fail(id, why);
+                        }
+                    }
+                },
+                _ = self.wake.notified() => {}
+            }
+        }
+        self.jobs()
+    }
+
+    /// take_ready: mark as Running every queued job that fits the limits, best first
+    fn take_ready(&self) -> Vec<(Job, CancelToken)> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut running = 0usize;
+        let mut per_host: HashMap<String, usize> = HashMap::new();
+        for e in inner.jobs.values().filter(|e| e.status == JobStatus::Running) {
+            running += 1;
+            *per_host.entry(e.host.clone()).or_default() += 1;
+        }
+
+        let mut queued: Vec<(Priority, u64, JobId)> = inner
+            .jobs
+            .values()
+            .filter(|e| e.status == JobStatus::Queued)
+            .map(|e| (e.job.priority, e.seq, e.job.id))
+            .collect();
+        // highest priority first, then FIFO
+        queued.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let mut ready = Vec::new();
+        for (_, _, id) in queued {
+            if running >= self.max_concurrent {
+                break;
+            }
+            let e = inner.jobs.get_mut(&id).expect("queued job");
+            let n = per_host.entry(e.host.clone()).or_default();
+            if *n >= self.per_host_limit {
+                continue;
+            }
+            *n += 1;
+            running += 1;
+            e.status = JobStatus::Running;
+            ready.push((e.job.clone(), e.cancel.clone()));
+        }
+        ready
+    }
+
+    fn finish(&self, id: JobId, res: Result<()>) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(e) = inner.jobs.get_mut(&id) else { return };
+        e.status = match (res, e.stop.take()) {
+            (Ok(()), _) => JobStatus::Done,
+            (Err(_), Some(StopReason::Pause)) => JobStatus::Paused,
+            (Err(_), Some(StopReason::Cancel)) => JobStatus::Cancelled,
+            (Err(err), None) => JobStatus::Failed(err.to_string()),
+        };
+    }
+
+    /// fail: the job's task died without returning (panic); its slot is free again
+    fn fail(&self, id: JobId, why: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = inner.jobs.get_mut(&id) {
+            e.stop = None;
+            e.status = JobStatus::Failed(why.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn panicking_job_fails_and_frees_its_slot() {
+        let cfg = Config { max_concurrent: 1, ..Config::default() };
+        let sched = Scheduler::new(&cfg);
+        let boom = sched.add("http://a.example/x", Priority::High);
+        let fine = sched.add("http://a.example/y", Priority::Normal);
+        let results = sched
+            .run(move |job, _cancel| {
+                Box::pin(async move {
+                    if job.id == boom {
+                        panic!("hostile header");
+                    }
+                    Ok(())
+                })
+            })
+            .await;
+        let status = |id| results.iter().find(|(j, _)| j.id == id).map(|(_, s)| s.clone());
+        assert_eq!(status(boom), Some(JobStatus::Failed("panicked".into())));
+        assert_eq!(status(fine), Some(JobStatus::Done));
+    }
+}
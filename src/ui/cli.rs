@@ -1,37 +1,211 @@
 //! CLI utilities: parse args, print metadata, ask confirmation
 //! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
 
-use clap::{Parser, ArgAction};
-use std::io::{self, Write};
+use clap::{Parser, Subcommand, ArgAction};
+use std::io::{self, IsTerminal, Write};
 use crate::util::format::format_size; // ← اضافه
+use crate::engine::config::Config;
+use crate::engine::types::OnConflict;
 
 
 #[derive(Parser, Debug)]
-#[command(name = "TondarDM", version, about = "Phase 0: metadata probe")]
+#[command(name = "TondarDM", version, about = "Segmented HTTP/HTTPS downloader")]
+#[command(arg_required_else_help = true)]
+#[command(after_help = "Links given without a command are downloaded, like `get`.")]
 pub struct Args {
-    /// Download link (HTTP/HTTPS)
-    pub url: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Download link(s) (HTTP/HTTPS) or .meta4/.metalink files; several are queued
+    pub urls: Vec<String>,
+    /// Read links from a file ("-" = stdin), one per line; indented `out=`, `dir=`,
+    /// `header=`, `referer=`, `checksum=`, `split=` lines apply to the link above (aria2 style)
+    #[arg(short = 'i', long = "input-file", value_name = "FILE", global = true)]
+    pub input: Option<String>,
+    /// Config file (default: $XDG_CONFIG_HOME/tondar/config.toml)
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    /// Output directory (overrides config `output_dir`)
+    #[arg(short = 'd', long = "dir", global = true)]
+    pub output_dir: Option<String>,
+    /// Max downloads running at once (overrides config `max_concurrent`)
+    #[arg(long, global = true)]
+    pub max_concurrent: Option<usize>,
+    /// Always start from zero instead of resuming existing files
+    #[arg(long, global = true)]
+    pub no_resume: bool,
+    /// Don't reserve disk space up front
+    #[arg(long, global = true)]
+    pub no_preallocate: bool,
     /// Optional Referer header
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub referer: Option<String>,
     /// Extra header(s): -H "Key: Value" (repeatable)
-    #[arg(short = 'H', long = "header", action = ArgAction::Append)]
+    #[arg(short = 'H', long = "header", action = ArgAction::Append, global = true)]
     pub headers: Vec<String>,
-    /// Cookie header (single line)
-    #[arg(long)]
+    /// Cookie header (single line), sent only to the hosts of the given links
+    #[arg(long, global = true)]
     pub cookie: Option<String>,
+    /// Read cookies from a Netscape cookies.txt (e.g. exported from a browser);
+    /// used for this run only, never copied into the profile jar
+    #[arg(long = "load-cookies", global = true)]
+    pub load_cookies: Option<String>,
+    /// Write all cookies (loaded and received) to a Netscape cookies.txt when done
+    #[arg(long = "save-cookies", global = true)]
+    pub save_cookies: Option<String>,
+    /// HTTP user (Basic/Digest); `user:password` also works
+    #[arg(long, global = true)]
+    pub user: Option<String>,
+    /// Password for --user
+    #[arg(long, global = true)]
+    pub password: Option<String>,
+    /// Bearer token sent as `Authorization: Bearer …`
+    #[arg(long, global = true)]
+    pub bearer: Option<String>,
+    /// Proxy for all requests: http://, https://, socks5://, socks5h:// (user:pass@ allowed);
+    /// overrides config `proxy` and HTTP(S)_PROXY
+    #[arg(long, global = true)]
+    pub proxy: Option<String>,
+    /// Hosts that skip the proxy, comma separated (`.example.com`, `10.0.0.1`, `*`);
+    /// overrides config `no_proxy` and NO_PROXY
+    #[arg(long = "no-proxy", value_name = "HOSTS", global = true)]
+    pub no_proxy: Option<String>,
     /// Override User-Agent
-    #[arg(long = "ua")]
+    #[arg(long = "ua", global = true)]
     pub ua: Option<String>,
     /// Send If-Range (ETag/Last-Modified) when resuming (off by default)
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub if_range: bool,
+    /// Number of connections (default: config `default_parts`)
+    #[arg(short = 'n', long, global = true)]
+    pub parts: Option<usize>,
+    /// Per-download speed cap, e.g. 512K or 2M (global cap: config `rate_limit_global`)
+    #[arg(long = "limit-rate", global = true)]
+    pub limit_rate: Option<String>,
+    /// Verify the finished file, e.g. sha256:9f86d0… (md5, sha1, sha256, sha512, blake3)
+    #[arg(long, global = true)]
+    pub checksum: Option<String>,
+    /// Another URL for the same file (repeatable); segments are pulled from all of them
+    #[arg(long = "mirror", action = ArgAction::Append, global = true)]
+    pub mirrors: Vec<String>,
+    /// Look for file.sha256 / SHA256SUMS etc. next to each link (config `find_checksum`)
+    #[arg(long, global = true)]
+    pub find_checksum: bool,
+    /// Tries per stalled request, the first included (overrides config `max_attempts`)
+    #[arg(long, global = true)]
+    pub max_attempts: Option<u32>,
+    /// Seconds to keep retrying a stalled request, 0 = no limit (config `retry_deadline`)
+    #[arg(long, global = true)]
+    pub retry_deadline: Option<u64>,
+    /// When the file name is taken: resume (only a matching unfinished download),
+    /// rename, skip, overwrite or ask (overrides config `on_conflict`)
+    #[arg(long, value_name = "POLICY", value_parser = clap::builder::ValueParser::new(str::parse::<OnConflict>), global = true)]
+    pub on_conflict: Option<OnConflict>,
+    /// Don't ask before downloading (also implied when stdin is not a terminal)
+    #[arg(short = 'y', long, global = true)]
+    pub yes: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Download links (what plain `TondarDM <links>` does)
+    Get {
+        /// Link(s) or .meta4/.metalink files
+        #[arg(required_unless_present = "input")]
+        urls: Vec<String>,
+    },
+    /// Show what a link would download (final URL, name, size, ranges, headers, proxy)
+    Probe {
+        url: String,
+        /// Probe with GET Range: bytes=0-0 instead of HEAD
+        #[arg(long)]
+        force_range: bool,
+    },
+    /// Continue downloads from their .state files (a file, or every one in a directory;
+    /// default: the output directory)
+    Resume {
+        path: Option<String>,
+    },
+    /// List unfinished downloads (.state files) in a directory (default: the output directory)
+    List {
+        path: Option<String>,
+    },
+    /// Links saved for later (queue.txt next to the config file)
+    Queue {
+        #[command(subcommand)]
+        action: QueueAction,
+    },
+    /// Show or edit the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum QueueAction {
+    /// Add links (and `-i` lists) to the queue; -H, --referer, -n and -d are saved with them
+    Add {
+        #[arg(required_unless_present = "input")]
+        urls: Vec<String>,
+    },
+    /// Print the queue
+    Show,
+    /// Download everything in the queue; finished links are taken off it
+    Run,
+    /// Empty the queue
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Print the effective config (file + TONDAR_* env)
+    Show,
+    /// Set one key in the config file, e.g. `config set output_dir ~/dl`
+    Set { key: String, value: String },
 }
 
 pub fn parse_args() -> Args {
     Args::parse()
 }
 
+/// apply_overrides: CLI flags win over config file and env
+pub fn apply_overrides(args: &Args, cfg: &mut Config) {
+    if let Some(d) = &args.output_dir {
+        cfg.output_dir = d.clone();
+    }
+    if let Some(n) = args.parts {
+        cfg.default_parts = n;
+    }
+    if let Some(n) = args.max_concurrent {
+        cfg.max_concurrent = n;
+    }
+    if args.no_resume {
+        cfg.resume = false;
+    }
+    if args.no_preallocate {
+        cfg.preallocate = false;
+    }
+    if args.find_checksum {
+        cfg.find_checksum = true;
+    }
+    if let Some(n) = args.max_attempts {
+        cfg.max_attempts = n.max(1);
+    }
+    if let Some(s) = args.retry_deadline {
+        cfg.retry_deadline = s;
+    }
+    if let Some(h) = &args.no_proxy {
+        cfg.no_proxy = Some(h.clone());
+    }
+    if let Some(p) = args.on_conflict {
+        cfg.on_conflict = p;
+    }
+    // -y answers every question, the conflict one included
+    if args.yes && cfg.on_conflict == OnConflict::Ask {
+        cfg.on_conflict = OnConflict::Resume;
+    }
+}
+
 pub fn print_meta(url: &str, name: &str, size: Option<u64>, ranges: bool) {
     println!("URL      : {url}");
     println!("Filename : {name}");
@@ -43,7 +217,32 @@ pub fn print_meta(url: &str, name: &str, size: Option<u64>, ranges: bool) {
     println!("Ranges   : {flag}");
 }
 
-pub fn confirm(question: &str) -> bool {
+/// choose: ask which of `choices` (a word, or its unique prefix); None without a terminal
+pub fn choose(question: &str, choices: &[&str]) -> Option<usize> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    loop {
+        print!("{question} [{}]: ", choices.join("/"));
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let answer = line.trim().to_ascii_lowercase();
+        let hits: Vec<usize> = (0..choices.len()).filter(|&i| !answer.is_empty() && choices[i].starts_with(&answer)).collect();
+        if let [i] = hits[..] {
+            return Some(i);
+        }
+    }
+}
+
+/// confirm: ask on a terminal; `yes` (-y) or a stdin that is not a terminal
+/// (scripts, cron, `-i -`) answers yes without asking
+pub fn confirm(question: &str, yes: bool) -> bool {
+    if yes || !io::stdin().is_terminal() {
+        return true;
+    }
     print!("{question} [y/N]: ");
     let _ = io::stdout().flush();
     let mut line = String::new();
@@ -53,3 +252,45 @@ pub fn confirm(question: &str) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Args {
+        Args::try_parse_from([&["TondarDM"], argv].concat()).unwrap()
+    }
+
+    #[test]
+    fn global_flags_before_a_command() {
+        let a = parse(&["-y", "resume"]);
+        assert!(a.yes && a.urls.is_empty());
+        assert!(matches!(a.command, Some(Command::Resume { path: None })));
+
+        let a = parse(&["-y", "list", "/tmp"]);
+        assert!(matches!(a.command, Some(Command::List { path: Some(ref p) }) if p == "/tmp"));
+
+        let a = parse(&["-y", "get", "https://a.example/x"]);
+        assert!(matches!(a.command, Some(Command::Get { ref urls }) if urls == &["https://a.example/x"]));
+        assert!(a.urls.is_empty());
+
+        let a = parse(&["-d", "/data", "queue", "show"]);
+        assert_eq!(a.output_dir.as_deref(), Some("/data"));
+        assert!(matches!(a.command, Some(Command::Queue { action: QueueAction::Show })));
+    }
+
+    #[test]
+    fn global_flags_after_a_command() {
+        let a = parse(&["queue", "run", "-y", "-d", "/data"]);
+        assert!(a.yes && a.output_dir.as_deref() == Some("/data"));
+        assert!(matches!(a.command, Some(Command::Queue { action: QueueAction::Run })));
+    }
+
+    #[test]
+    fn bare_links_download() {
+        let a = parse(&["-y", "-n", "4", "https://a.example/x", "https://b.example/y"]);
+        assert!(a.command.is_none());
+        assert_eq!(a.urls, ["https://a.example/x", "https://b.example/y"]);
+        assert_eq!(a.parts, Some(4));
+    }
+}
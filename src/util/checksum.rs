@@ -0,0 +1,392 @@
+//! Utility: checksums (md5 / sha1 / sha256 / sha512 / blake3)
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - Checksum: an expected digest, parsed from "algo:hex"
+//! - PrefixHasher: hashes bytes [0, upto) of a file as they are written, and can
+//!   catch up from disk (resume) or start over (restart from zero)
+//! - Pieces: per-piece digests (metalink) to pinpoint which byte ranges are bad
+//! - from_digest_header / from_sidecar: expected digests published by the server
+//!   (`Digest`, `Repr-Digest`, `file.sha256`, `SHA256SUMS`, …)
+
+use crate::engine::prelude::*;
+use crate::engine::types::RangeReq;
+use base64::Engine as _;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use std::io::SeekFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algo {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algo {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algo::Md5 => "md5",
+            Algo::Sha1 => "sha1",
+            Algo::Sha256 => "sha256",
+            Algo::Sha512 => "sha512",
+            Algo::Blake3 => "blake3",
+        }
+    }
+
+    /// strength: higher is preferred when a server offers several digests
+    pub fn strength(self) -> u8 {
+        match self {
+            Algo::Md5 => 0,
+            Algo::Sha1 => 1,
+            Algo::Blake3 => 2,
+            Algo::Sha256 => 3,
+            Algo::Sha512 => 4,
+        }
+    }
+
+    /// hex_len: length of the digest in hex characters
+    pub fn hex_len(self) -> usize {
+        match self {
+            Algo::Md5 => 32,
+            Algo::Sha1 => 40,
+            Algo::Sha256 | Algo::Blake3 => 64,
+            Algo::Sha512 => 128,
+        }
+    }
+}
+
+impl FromStr for Algo {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(Algo::Md5),
+            "sha1" => Ok(Algo::Sha1),
+            "sha256" => Ok(Algo::Sha256),
+            "sha512" => Ok(Algo::Sha512),
+            "blake3" => Ok(Algo::Blake3),
+            _ => Err(format!("unsupported checksum algorithm {s:?}")),
+        }
+    }
+}
+
+/// Checksum: expected digest (hex is kept lowercase)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algo: Algo,
+    pub hex: String,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+    /// "sha256:9f86d0…" (also accepts "sha256=…")
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let (algo, hex) = s
+            .split_once([':', '='])
+            .ok_or_else(|| format!("expected algo:hex, got {s:?}"))?;
+        let algo: Algo = algo.trim().parse()?;
+        let hex = hex.trim().to_ascii_lowercase();
+        if hex.len() != algo.hex_len() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("{} digest must be {} hex characters", algo.name(), algo.hex_len()));
+        }
+        Ok(Checksum { algo, hex })
+    }
+}
+
+impl Checksum {
+    /// from_bytes: raw digest bytes (as carried in headers) → Checksum
+    pub fn from_bytes(algo: Algo, bytes: &[u8]) -> Option<Self> {
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        (hex.len() == algo.hex_len()).then_some(Checksum { algo, hex })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algo.name(), self.hex)
+    }
+}
+
+enum Inner {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Inner {
+    fn new(algo: Algo) -> Self {
+        match algo {
+            Algo::Md5 => Inner::Md5(Md5::new()),
+            Algo::Sha1 => Inner::Sha1(Sha1::new()),
+            Algo::Sha256 => Inner::Sha256(Sha256::new()),
+            Algo::Sha512 => Inner::Sha512(Sha512::new()),
+            Algo::Blake3 => Inner::Blake3(Box::default()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Inner::Md5(h) => h.update(data),
+            Inner::Sha1(h) => h.update(data),
+            Inner::Sha256(h) => h.update(data),
+            Inner::Sha512(h) => h.update(data),
+            Inner::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn finalize_hex(self) -> String {
+        let bytes: Vec<u8> = match self {
+            Inner::Md5(h) => h.finalize().to_vec(),
+            Inner::Sha1(h) => h.finalize().to_vec(),
+            Inner::Sha256(h) => h.finalize().to_vec(),
+            Inner::Sha512(h) => h.finalize().to_vec(),
+            Inner::Blake3(h) => h.finalize().as_bytes().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// PrefixHasher: running digest of the first `upto` bytes of a file.
+pub struct PrefixHasher {
+    algo: Algo,
+    inner: Inner,
+    upto: u64,
+}
+
+impl PrefixHasher {
+    pub fn new(algo: Algo) -> Self {
+        Self { algo, inner: Inner::new(algo), upto: 0 }
+    }
+
+    pub fn upto(&self) -> u64 {
+        self.upto
+    }
+
+    /// update: feed bytes written right after the current prefix
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+        self.upto += data.len() as u64;
+    }
+
+    /// catch_up: make the digest cover exactly `[0, offset)` of `path`,
+    /// re-reading from disk what was written without passing through us
+    /// (resumed prefix) and starting over if the file went backwards.
+    pub async fn catch_up(&mut self, path: &str, offset: u64) -> Result<()> {
+        if offset < self.upto {
+            self.inner = Inner::new(self.algo);
+            self.upto = 0;
+        }
+        if offset == self.upto {
+            return Ok(());
+        }
+        let mut f = File::open(path).await.map_err(DmError::Io)?;
+        f.seek(SeekFrom::Start(self.upto)).await.map_err(DmError::Io)?;
+        let mut buf = vec![0u8; 256 * 1024];
+        while self.upto < offset {
+            let want = buf.len().min((offset - self.upto) as usize);
+            let n = f.read(&mut buf[..want]).await.map_err(DmError::Io)?;
+            if n == 0 {
+                return Err(DmError::Truncated { expected: offset, got: self.upto });
+            }
+            self.update(&buf[..n]);
+        }
+        Ok(())
+    }
+
+    /// verify: finish the digest and compare with `expected`
+    pub fn verify(self, expected: &Checksum) -> Result<()> {
+        let actual = self.inner.finalize_hex();
+        if actual == expected.hex {
+            Ok(())
+        } else {
+            Err(DmError::ChecksumMismatch {
+                algo: expected.algo.name().to_string(),
+                expected: expected.hex.clone(),
+                actual,
+            })
+        }
+    }
+}
+
+/// Pieces: file split into `length`-byte pieces, one digest each (last piece may be short)
+#[derive(Debug, Clone)]
+pub struct Pieces {
+    pub algo: Algo,
+    pub length: u64,
+    pub hashes: Vec<String>,
+}
+
+impl Pieces {
+    /// range: bytes covered by piece `idx` in a file of `total` bytes
+    pub fn range(&self, idx: usize, total: u64) -> RangeReq {
+        let start = idx as u64 * self.length;
+        RangeReq { start, end: (start + self.length).min(total).saturating_sub(1) }
+    }
+
+    /// bad_pieces: (index, actual digest) of every piece of `path` that does not
+    /// match (a file too short to contain a piece fails that piece)
+    pub async fn bad_pieces(&self, path: &str) -> Result<Vec<(usize, String)>> {
+        let mut f = File::open(path).await.map_err(DmError::Io)?;
+        let mut buf = vec![0u8; self.length.min(4 << 20) as usize];
+        let mut bad = Vec::new();
+        for (idx, expected) in self.hashes.iter().enumerate() {
+            let mut h = Inner::new(self.algo);
+            let mut left = self.length;
+            while left > 0 {
+                let want = buf.len().min(left as usize);
+                let n = f.read(&mut buf[..want]).await.map_err(DmError::Io)?;
+                if n == 0 {
+                    break;
+                }
+                h.update(&buf[..n]);
+                left -= n as u64;
+            }
+            let actual = h.finalize_hex();
+            if actual != *expected {
+                bad.push((idx, actual));
+            }
+        }
+        Ok(bad)
+    }
+}
+
+/// from_digest_header: strongest usable digest in an RFC 3230 `Digest`
+/// (`SHA-256=<base64>`) or RFC 9530 `Repr-Digest` (`sha-256=:<base64>:`) value
+pub fn from_digest_header(value: &str) -> Option<Checksum> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let (name, b64) = item.split_once('=')?;
+            // RFC 3230 calls SHA-1 plain "SHA"
+            let algo: Algo = match name.trim() {
+                n if n.eq_ignore_ascii_case("sha") => Algo::Sha1,
+                n => n.parse().ok()?,
+            };
+            let b64 = b64.trim().trim_matches(':');
+            let bytes = base64::engine::general_purpose::STANDARD.decode(b64).ok()?;
+            Checksum::from_bytes(algo, &bytes)
+        })
+        .max_by_key(|c| c.algo.strength())
+}
+
+/// from_sidecar: digest for `filename` in a checksum file. Understands
+/// `sha256sum` output (`<hex>  [*]name`), BSD tags (`SHA256 (name) = <hex>`)
+/// and a lone `<hex>` (per-file sidecars like `file.iso.sha256`).
+pub fn from_sidecar(text: &str, algo: Algo, filename: &str) -> Option<Checksum> {
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).collect();
+    let parse = |hex: &str| format!("{}:{}", algo.name(), hex.trim()).parse::<Checksum>().ok();
+
+    for line in &lines {
+        // BSD style: SHA256 (name) = hex
+        if let Some((tag, rest)) = line.split_once(" (") {
+            if let Some((name, hex)) = rest.rsplit_once(") = ") {
+                if name == filename && tag.parse::<Algo>().ok() == Some(algo) {
+                    return parse(hex);
+                }
+                continue;
+            }
+        }
+        // GNU style: hex, two spaces (or " *" for binary mode), name
+        if let Some((hex, name)) = line.split_once(char::is_whitespace) {
+            let name = name.trim_start().trim_start_matches('*');
+            if name == filename || name.rsplit('/').next() == Some(filename) {
+                return parse(hex);
+            }
+        }
+    }
+    match lines.as_slice() {
+        [only] if !only.contains(char::is_whitespace) => parse(only),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // digests of "abc"
+    const SHA1: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const SHA256_B64: &str = "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=";
+    const SHA1_B64: &str = "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=";
+
+    #[test]
+    fn checksum_from_str() {
+        let c: Checksum = format!("SHA-256:{}", SHA256.to_uppercase()).parse().unwrap();
+        assert_eq!((c.algo, c.hex.as_str()), (Algo::Sha256, SHA256));
+        assert_eq!(format!("sha256={SHA256}").parse::<Checksum>().unwrap(), c);
+        assert_eq!(c.to_string(), format!("sha256:{SHA256}"));
+
+        for bad in [
+            format!("sha256:{}", &SHA256[1..]),
+            format!("sha256:{SHA256}0"),
+            format!("sha1:{SHA256}"),
+            format!("sha256:{}g", &SHA256[1..]),
+            format!("crc32:{SHA1}"),
+            SHA256.to_string(),
+        ] {
+            assert!(bad.parse::<Checksum>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sidecar_gnu_and_bsd_lines() {
+        let gnu = format!("{SHA256}  file.iso\n");
+        assert_eq!(from_sidecar(&gnu, Algo::Sha256, "file.iso").unwrap().hex, SHA256);
+        let binary = format!("{SHA256} *file.iso\n");
+        assert_eq!(from_sidecar(&binary, Algo::Sha256, "file.iso").unwrap().hex, SHA256);
+        let nested = format!("{SHA256}  ./dist/file.iso\n");
+        assert_eq!(from_sidecar(&nested, Algo::Sha256, "file.iso").unwrap().hex, SHA256);
+        let bsd = format!("SHA256 (file.iso) = {SHA256}\n");
+        assert_eq!(from_sidecar(&bsd, Algo::Sha256, "file.iso").unwrap().hex, SHA256);
+        // a BSD tag of another algorithm is not ours
+        assert!(from_sidecar(&format!("SHA1 (file.iso) = {SHA1}\n"), Algo::Sha256, "file.iso").is_none());
+        // " (" in a GNU file name is not a BSD line
+        let paren = format!("{SHA256}  file (1).iso\n");
+        assert_eq!(from_sidecar(&paren, Algo::Sha256, "file (1).iso").unwrap().hex, SHA256);
+    }
+
+    #[test]
+    fn sidecar_picks_our_file() {
+        let other = "0".repeat(64);
+        let sums = format!("# SHA256SUMS\n{other}  file.iso.torrent\n{SHA256}  file.iso\n{other}  other.iso\n");
+        assert_eq!(from_sidecar(&sums, Algo::Sha256, "file.iso").unwrap().hex, SHA256);
+        assert!(from_sidecar(&sums, Algo::Sha256, "missing.iso").is_none());
+        // a lone hash only counts when it is the whole file
+        assert_eq!(from_sidecar(&format!("{SHA256}\n"), Algo::Sha256, "x").unwrap().hex, SHA256);
+        assert!(from_sidecar(&format!("{SHA256}\n{other}\n"), Algo::Sha256, "x").is_none());
+    }
+
+    #[test]
+    fn sidecar_bad_lengths() {
+        assert!(from_sidecar(&format!("{SHA1}  file.iso\n"), Algo::Sha256, "file.iso").is_none());
+        assert!(from_sidecar(&format!("{}\n", &SHA256[..63]), Algo::Sha256, "x").is_none());
+        assert!(from_sidecar("<html>not found</html>\n", Algo::Sha256, "x").is_none());
+    }
+
+    #[test]
+    fn digest_headers() {
+        // RFC 9530 Repr-Digest: sf-binary between colons
+        let c = from_digest_header(&format!("sha-256=:{SHA256_B64}:")).unwrap();
+        assert_eq!((c.algo, c.hex.as_str()), (Algo::Sha256, SHA256));
+        // RFC 3230 Digest: bare base64, "SHA" = SHA-1; the strongest one wins
+        let c = from_digest_header(&format!("SHA={SHA1_B64}, SHA-256={SHA256_B64}")).unwrap();
+        assert_eq!(c.algo, Algo::Sha256);
+        let c = from_digest_header(&format!("sha={SHA1_B64}")).unwrap();
+        assert_eq!((c.algo, c.hex.as_str()), (Algo::Sha1, SHA1));
+        // wrong length for the algorithm, bad base64, unknown algorithms
+        assert!(from_digest_header(&format!("sha-512=:{SHA256_B64}:")).is_none());
+        assert!(from_digest_header("sha-256=:not base64!:").is_none());
+        assert!(from_digest_header(&format!("unixsum=30637, crc32c=:{SHA1_B64}:")).is_none());
+    }
+}
@@ -0,0 +1,161 @@
+//! Utility: make a server-supplied file name safe to create in the output directory
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - sanitize(): last path component only, no control/reserved characters,
+//!   no bidi overrides ("setup\u{202E}txt.exe" shows as "setupexe.txt"),
+//!   no Windows device names, bounded length; never empty, never "." or ".."
+//!   Names are NFC, so the same Persian name from a macOS (NFD) server and from
+//!   a Windows one is one file, not two that look alike
+//! - numbered(): `name (n).ext` for a name that is already taken
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest name we create, in bytes: leaves room for PART_SUFFIX and the
+/// " (1)" of a rename under the usual 255-byte limit
+pub const MAX_NAME_BYTES: usize = 200;
+
+/// Used when nothing usable is left
+pub const FALLBACK_NAME: &str = "download.bin";
+
+/// sanitize: turn `name` (Content-Disposition, URL segment, metalink, `out=`)
+/// into a plain file name that cannot leave the directory it is joined to
+pub fn sanitize(name: &str) -> String {
+    // "../../.bashrc", "C:\\Windows\\x", "/etc/passwd" → the part after the last separator
+    let base = name.rsplit(['/', '\\']).next().unwrap_or(name);
+
+    let cleaned: String = base
+        .nfc()
+        .filter(|&c| !c.is_control() && !is_bidi_control(c))
+        .map(|c| match c {
+            // invalid on Windows/FAT; harmless to replace everywhere
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    // no hidden files from a server, and no trailing dots/spaces (Windows drops them)
+    let trimmed = cleaned.trim().trim_start_matches('.').trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        return FALLBACK_NAME.to_string();
+    }
+
+    let name = if is_reserved(trimmed) { format!("_{trimmed}") } else { trimmed.to_string() };
+    truncate(&name, MAX_NAME_BYTES)
+}
+
+/// numbered: "setup.exe" → "setup (1).exe", "src.tar.gz" → "src (1).tar.gz"
+pub fn numbered(name: &str, n: u32) -> String {
+    let lower = name.to_ascii_lowercase();
+    let split = match lower.rfind(".tar.") {
+        Some(i) if i > 0 => Some(i),
+        _ => name.rfind('.').filter(|&i| i > 0),
+    };
+    match split {
+        Some(i) => format!("{} ({n}){}", &name[..i], &name[i..]),
+        None => format!("{name} ({n})"),
+    }
+}
+
+/// is_bidi_control: the invisible marks that reorder how a name is displayed.
+/// ZWNJ/ZWJ stay: Persian names need them
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// is_reserved: CON, PRN, AUX, NUL, COM1–9, LPT1–9, with or without an extension
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end().to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        s => {
+            (s.starts_with("COM") || s.starts_with("LPT"))
+                && s.len() == 4
+                && s.as_bytes()[3].is_ascii_digit()
+                && s.as_bytes()[3] != b'0'
+        }
+    }
+}
+
+/// truncate: cut to `max` bytes on a char boundary, keeping a short extension
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+    let ext = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= 16 => &name[i..],
+        _ => "",
+    };
+    let mut cut = max - ext.len();
+    while !name.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    format!("{}{ext}", &name[..cut])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_last_component_is_kept() {
+        assert_eq!(sanitize("../../.bashrc"), "bashrc");
+        assert_eq!(sanitize("/etc/passwd"), "passwd");
+        assert_eq!(sanitize("C:\\x"), "x");
+        assert_eq!(sanitize("a/b\\c.zip"), "c.zip");
+    }
+
+    #[test]
+    fn dots_and_empty_names_fall_back() {
+        for name in ["", "   ", ".", "..", "../..", "/", "...", "\u{202E}"] {
+            assert_eq!(sanitize(name), FALLBACK_NAME, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn percent_encoded_separators_are_decoded_first() {
+        let decoded = percent_encoding::percent_decode_str("..%2F..%2Fetc%2Fpasswd").decode_utf8_lossy();
+        assert_eq!(sanitize(&decoded), "passwd");
+        let decoded = percent_encoding::percent_decode_str("..%5C..%5Cboot.ini").decode_utf8_lossy();
+        assert_eq!(sanitize(&decoded), "boot.ini");
+    }
+
+    #[test]
+    fn windows_names_and_characters() {
+        assert_eq!(sanitize("CON.txt"), "_CON.txt");
+        assert_eq!(sanitize("com1"), "_com1");
+        assert_eq!(sanitize("COM0.txt"), "COM0.txt");
+        assert_eq!(sanitize("a<b>c:d\"e|f?g*.zip"), "a_b_c_d_e_f_g_.zip");
+        assert_eq!(sanitize("name. . "), "name");
+        assert_eq!(sanitize("tab\there\n.zip"), "tabhere.zip");
+    }
+
+    #[test]
+    fn bidi_overrides_are_removed() {
+        assert_eq!(sanitize("setup\u{202E}txt.exe"), "setuptxt.exe");
+        assert_eq!(sanitize("\u{2067}doc\u{2069}.pdf"), "doc.pdf");
+        // ZWNJ is part of Persian spelling
+        assert_eq!(sanitize("می\u{200C}خواهم.txt"), "می\u{200C}خواهم.txt");
+    }
+
+    #[test]
+    fn long_multibyte_names_are_cut_on_a_char_boundary() {
+        let name = format!("{}.zip", "ف".repeat(150)); // 300 bytes + extension
+        let out = sanitize(&name);
+        assert!(out.len() <= MAX_NAME_BYTES, "{} bytes", out.len());
+        assert!(out.ends_with(".zip"));
+        assert!(out.trim_end_matches(".zip").chars().all(|c| c == 'ف'));
+    }
+
+    #[test]
+    fn names_are_nfc() {
+        assert_eq!(sanitize("cafe\u{0301}.txt"), "caf\u{00E9}.txt");
+    }
+
+    #[test]
+    fn numbered_keeps_the_extension() {
+        assert_eq!(numbered("setup.exe", 1), "setup (1).exe");
+        assert_eq!(numbered("src.tar.gz", 2), "src (2).tar.gz");
+        assert_eq!(numbered("README", 3), "README (3)");
+        assert_eq!(numbered(".hidden", 1), ".hidden (1)");
+    }
+}
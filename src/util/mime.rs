@@ -0,0 +1,190 @@
+//! Utility: file type from Content-Type and from the first bytes of the file
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - essence(): "Application/ZIP; charset=x" → "application/zip"
+//! - extension_for(): MIME type → usual extension (None for octet-stream & unknowns)
+//! - sniff(): magic numbers → (MIME type, extensions that fit those bytes)
+//! - extension(): the extension a file name already has, if it looks like one
+
+/// (MIME type, extension); the first entry of a type is the one we add
+const TABLE: &[(&str, &str)] = &[
+    ("application/zip", "zip"),
+    ("application/x-zip-compressed", "zip"),
+    ("application/gzip", "gz"),
+    ("application/x-gzip", "gz"),
+    ("application/x-tar", "tar"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/vnd.rar", "rar"),
+    ("application/x-rar-compressed", "rar"),
+    ("application/x-xz", "xz"),
+    ("application/x-bzip2", "bz2"),
+    ("application/zstd", "zst"),
+    ("application/pdf", "pdf"),
+    ("application/json", "json"),
+    ("application/xml", "xml"),
+    ("application/epub+zip", "epub"),
+    ("application/java-archive", "jar"),
+    ("application/vnd.android.package-archive", "apk"),
+    ("application/vnd.debian.binary-package", "deb"),
+    ("application/x-debian-package", "deb"),
+    ("application/x-rpm", "rpm"),
+    ("application/x-iso9660-image", "iso"),
+    ("application/x-apple-diskimage", "dmg"),
+    ("application/x-msdownload", "exe"),
+    ("application/vnd.microsoft.portable-executable", "exe"),
+    ("application/x-msi", "msi"),
+    ("application/msword", "doc"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/avif", "avif"),
+    ("image/svg+xml", "svg"),
+    ("audio/mpeg", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("audio/ogg", "ogg"),
+    ("audio/flac", "flac"),
+    ("audio/wav", "wav"),
+    ("audio/x-wav", "wav"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("video/x-matroska", "mkv"),
+    ("video/quicktime", "mov"),
+    ("text/plain", "txt"),
+    ("text/html", "html"),
+    ("text/csv", "csv"),
+];
+
+/// essence: the bare, lowercase type of a Content-Type value
+pub fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+/// extension_for: usual extension of a MIME type
+pub fn extension_for(mime: &str) -> Option<&'static str> {
+    let mime = essence(mime);
+    TABLE.iter().find(|(m, _)| *m == mime).map(|(_, ext)| *ext)
+}
+
+/// extension: "a.tar.gz" → "gz", "setup.exe" → "exe", "download" → None,
+/// "v1.2" → None (too odd to be an extension)
+pub fn extension(name: &str) -> Option<&str> {
+    let (stem, ext) = name.rsplit_once('.')?;
+    let plausible = !stem.is_empty()
+        && (1..=5).contains(&ext.len())
+        && ext.chars().all(|c| c.is_ascii_alphanumeric())
+        && ext.chars().any(|c| c.is_ascii_alphabetic());
+    plausible.then_some(ext)
+}
+
+/// sniff: what the leading bytes say the file is; the extensions are every one
+/// those bytes are valid for (a .docx is a zip), the first being the plain one
+pub fn sniff(head: &[u8]) -> Option<(&'static str, &'static [&'static str])> {
+    let at = |off: usize, magic: &[u8]| head.get(off..off + magic.len()) == Some(magic);
+    let found: (&str, &[&str]) = if at(0, b"PK\x03\x04") {
+        ("application/zip", &["zip", "docx", "xlsx", "pptx", "odt", "ods", "jar", "apk", "epub", "xpi"])
+    } else if at(0, b"%PDF-") {
+        ("application/pdf", &["pdf"])
+    } else if at(0, b"\x89PNG\r\n\x1a\n") {
+        ("image/png", &["png"])
+    } else if at(0, b"\xff\xd8\xff") {
+        ("image/jpeg", &["jpg", "jpeg"])
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        ("image/gif", &["gif"])
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        ("image/webp", &["webp"])
+    } else if at(0, b"RIFF") && at(8, b"WAVE") {
+        ("audio/wav", &["wav"])
+    } else if at(0, b"\x1f\x8b") {
+        ("application/gzip", &["gz", "tgz"])
+    } else if at(0, b"7z\xbc\xaf\x27\x1c") {
+        ("application/x-7z-compressed", &["7z"])
+    } else if at(0, b"Rar!\x1a\x07") {
+        ("application/vnd.rar", &["rar"])
+    } else if at(0, b"\xfd7zXZ\x00") {
+        ("application/x-xz", &["xz", "txz"])
+    } else if at(0, b"BZh") {
+        ("application/x-bzip2", &["bz2", "tbz"])
+    } else if at(0, b"\x28\xb5\x2f\xfd") {
+        ("application/zstd", &["zst"])
+    } else if at(0, b"MZ") {
+        ("application/vnd.microsoft.portable-executable", &["exe", "dll", "sys", "efi"])
+    } else if at(0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1") {
+        ("application/x-msi", &["msi", "doc", "xls", "ppt", "msg"])
+    } else if at(4, b"ftyp") {
+        ("video/mp4", &["mp4", "m4a", "m4v", "mov", "3gp", "heic", "avif"])
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        ("video/x-matroska", &["mkv", "webm", "mka"])
+    } else if at(0, b"OggS") {
+        ("audio/ogg", &["ogg", "oga", "ogv", "opus"])
+    } else if at(0, b"fLaC") {
+        ("audio/flac", &["flac"])
+    } else if at(0, b"ID3") {
+        ("audio/mpeg", &["mp3"])
+    } else if at(0, b"!<arch>\ndebian") {
+        ("application/vnd.debian.binary-package", &["deb"])
+    } else if at(0, b"\xed\xab\xee\xdb") {
+        ("application/x-rpm", &["rpm"])
+    } else if at(257, b"ustar") {
+        ("application/x-tar", &["tar"])
+    } else {
+        return None;
+    };
+    Some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kind(head: &[u8]) -> Option<&'static str> {
+        sniff(head).map(|(mime, _)| mime)
+    }
+
+    #[test]
+    fn magic_bytes() {
+        assert_eq!(kind(b"PK\x03\x04\x14\x00\x00\x00"), Some("application/zip"));
+        assert_eq!(kind(b"\x1f\x8b\x08\x00\x00\x00\x00\x00"), Some("application/gzip"));
+        assert_eq!(kind(b"%PDF-1.7\n%\xe2\xe3\xcf\xd3"), Some("application/pdf"));
+        assert_eq!(kind(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"), Some("image/png"));
+        assert_eq!(kind(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+        assert_eq!(kind(b"\x00\x00\x00\x20ftypisom"), Some("video/mp4"));
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(kind(&tar), Some("application/x-tar"));
+    }
+
+    #[test]
+    fn text_and_short_heads_are_unknown() {
+        assert_eq!(kind(b"Hello, world.\nThis is plain text.\n"), None);
+        assert_eq!(kind("سلام دنیا".as_bytes()), None);
+        assert_eq!(kind(b""), None);
+        // a prefix of a signature is not the signature
+        assert_eq!(kind(b"\x89PNG"), None);
+        assert_eq!(kind(b"PK"), None);
+        // the signature must be at the start
+        assert_eq!(kind(b" %PDF-1.4"), None);
+    }
+
+    #[test]
+    fn a_zip_fits_its_relatives() {
+        let (_, exts) = sniff(b"PK\x03\x04").unwrap();
+        assert_eq!(exts[0], "zip");
+        assert!(exts.contains(&"docx") && exts.contains(&"apk"));
+    }
+
+    #[test]
+    fn types_and_extensions() {
+        assert_eq!(essence("Application/ZIP; charset=binary"), "application/zip");
+        assert_eq!(extension_for("application/x-gzip"), Some("gz"));
+        assert_eq!(extension_for("application/octet-stream"), None);
+        assert_eq!(extension("a.tar.gz"), Some("gz"));
+        assert_eq!(extension("download"), None);
+        assert_eq!(extension("v1.2"), None);
+        assert_eq!(extension(".bashrc"), None);
+    }
+}
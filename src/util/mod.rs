@@ -1 +1,5 @@
 pub mod format;
+pub mod rate;
+pub mod checksum;
+pub mod filename;
+pub mod mime;
@@ -0,0 +1,124 @@
+//! Utility: bandwidth limiting (token bucket) + human rate parsing
+//! Author: Ali Asadi | Team: Persian Developer Team | Email: persianbsd@gmail.com
+//!
+//! - parse_rate(): "512K" / "2M" / "1.5MB" / "1.5MiB" → bytes per second
+//! - RateLimiter: shared token bucket; clones draw from the same bucket
+
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration, Instant};
+
+/// parse_rate: parse a human rate into bytes/sec (K/M/G are powers of 1024).
+/// Returns None for empty, zero, or malformed input.
+pub fn parse_rate(s: &str) -> Option<u64> {
+    let t = s.trim();
+    let t = t.strip_suffix("/s").unwrap_or(t);
+    let t = t.strip_suffix(['B', 'b']).unwrap_or(t);
+    // "MiB" and "MB" mean the same here
+    let t = t.strip_suffix(['i', 'I']).filter(|u| u.ends_with(['k', 'K', 'm', 'M', 'g', 'G'])).unwrap_or(t);
+    let (num, mult) = match t.chars().last()? {
+        'k' | 'K' => (&t[..t.len() - 1], 1024.0),
+        'm' | 'M' => (&t[..t.len() - 1], 1024.0 * 1024.0),
+        'g' | 'G' => (&t[..t.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (t, 1.0),
+    };
+    let v: f64 = num.trim().parse().ok()?;
+    let bytes = (v * mult).round();
+    (bytes.is_finite() && bytes >= 1.0).then_some(bytes as u64)
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+/// RateLimiter: token bucket holding up to one second of tokens.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket { rate, tokens: rate, last: Instant::now() })),
+        }
+    }
+
+    /// acquire: take `n` bytes worth of tokens, sleeping off any debt.
+    /// Chunks larger than the bucket are allowed; the caller just waits longer.
+    pub async fn acquire(&self, n: u64) {
+        let wait = self.take(n, Instant::now());
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+
+    /// take: refill up to `now` (never past one second's worth), draw `n`, and
+    /// return how long the debt takes to pay off
+    fn take(&self, n: u64, now: Instant) -> Duration {
+        let mut b = self.bucket.lock().unwrap();
+        let refill = now.saturating_duration_since(b.last).as_secs_f64() * b.rate;
+        b.tokens = (b.tokens + refill).min(b.rate);
+        b.last = b.last.max(now);
+        b.tokens -= n as f64;
+        if b.tokens < 0.0 {
+            Duration::from_secs_f64(-b.tokens / b.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("500K"), Some(500 * 1024));
+        assert_eq!(parse_rate("500k"), Some(500 * 1024));
+        assert_eq!(parse_rate("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_rate(" 2MB/s "), Some(2 * 1024 * 1024));
+        assert_eq!(parse_rate("1.5MiB"), Some(1024 * 1024 * 3 / 2));
+        assert_eq!(parse_rate("1.5mib/s"), Some(1024 * 1024 * 3 / 2));
+        assert_eq!(parse_rate("1G"), Some(1 << 30));
+        assert_eq!(parse_rate("300"), Some(300));
+        assert_eq!(parse_rate("300B"), Some(300));
+    }
+
+    #[test]
+    fn bad_rates() {
+        for bad in ["", "  ", "0", "0K", "-5K", "K", "fast", "1.5X", "1..5M", "2Mi B", "iB", "0.0001", "NaN", "infK"] {
+            assert_eq!(parse_rate(bad), None, "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn bucket_refills_up_to_one_second() {
+        let rl = RateLimiter::new(1000);
+        let t0 = Instant::now();
+        // a full bucket to start with, then debt
+        assert_eq!(rl.take(1000, t0), Duration::ZERO);
+        assert_eq!(rl.take(500, t0), Duration::from_millis(500));
+        // half a second pays the debt off, nothing more
+        assert_eq!(rl.take(0, t0 + Duration::from_millis(500)), Duration::ZERO);
+        assert_eq!(rl.take(1, t0 + Duration::from_millis(500)), Duration::from_millis(1));
+
+        // a long idle spell is capped at the burst (one second of tokens)
+        let later = t0 + Duration::from_secs(60);
+        assert_eq!(rl.take(1000, later), Duration::ZERO);
+        assert_eq!(rl.take(250, later), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn clones_share_the_bucket() {
+        let a = RateLimiter::new(100);
+        let b = a.clone();
+        let t0 = Instant::now();
+        assert_eq!(a.take(100, t0), Duration::ZERO);
+        assert_eq!(b.take(50, t0), Duration::from_millis(500));
+    }
+}